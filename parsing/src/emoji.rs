@@ -0,0 +1,340 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+
+/// Shortcodes and the unicode emoji they stand for, using the names Discord uses
+///
+/// When an emoji has several shortcodes the first one is used to display it
+static EMOJI: &[(&str, &str)] = &[
+    ("smile", "😄"),
+    ("grinning", "😀"),
+    ("smiley", "😃"),
+    ("grin", "😁"),
+    ("laughing", "😆"),
+    ("sweat_smile", "😅"),
+    ("joy", "😂"),
+    ("rofl", "🤣"),
+    ("slight_smile", "🙂"),
+    ("upside_down", "🙃"),
+    ("wink", "😉"),
+    ("blush", "😊"),
+    ("innocent", "😇"),
+    ("heart_eyes", "😍"),
+    ("kissing_heart", "😘"),
+    ("yum", "😋"),
+    ("stuck_out_tongue", "😛"),
+    ("stuck_out_tongue_winking_eye", "😜"),
+    ("zany_face", "🤪"),
+    ("thinking", "🤔"),
+    ("zipper_mouth", "🤐"),
+    ("neutral_face", "😐"),
+    ("expressionless", "😑"),
+    ("no_mouth", "😶"),
+    ("smirk", "😏"),
+    ("unamused", "😒"),
+    ("rolling_eyes", "🙄"),
+    ("grimacing", "😬"),
+    ("relieved", "😌"),
+    ("pensive", "😔"),
+    ("sleepy", "😪"),
+    ("sleeping", "😴"),
+    ("mask", "😷"),
+    ("nerd", "🤓"),
+    ("sunglasses", "😎"),
+    ("confused", "😕"),
+    ("worried", "😟"),
+    ("slight_frown", "🙁"),
+    ("open_mouth", "😮"),
+    ("hushed", "😯"),
+    ("astonished", "😲"),
+    ("flushed", "😳"),
+    ("pleading_face", "🥺"),
+    ("cry", "😢"),
+    ("sob", "😭"),
+    ("scream", "😱"),
+    ("confounded", "😖"),
+    ("persevere", "😣"),
+    ("disappointed", "😞"),
+    ("sweat", "😓"),
+    ("weary", "😩"),
+    ("tired_face", "😫"),
+    ("yawning_face", "🥱"),
+    ("triumph", "😤"),
+    ("rage", "😡"),
+    ("angry", "😠"),
+    ("skull", "💀"),
+    ("poop", "💩"),
+    ("clown", "🤡"),
+    ("ghost", "👻"),
+    ("alien", "👽"),
+    ("robot", "🤖"),
+    ("see_no_evil", "🙈"),
+    ("hear_no_evil", "🙉"),
+    ("speak_no_evil", "🙊"),
+    ("wave", "👋"),
+    ("raised_hand", "✋"),
+    ("ok_hand", "👌"),
+    ("v", "✌️"),
+    ("crossed_fingers", "🤞"),
+    ("metal", "🤘"),
+    ("call_me", "🤙"),
+    ("point_left", "👈"),
+    ("point_right", "👉"),
+    ("point_up_2", "👆"),
+    ("point_down", "👇"),
+    ("thumbsup", "👍"),
+    ("+1", "👍"),
+    ("thumbsdown", "👎"),
+    ("-1", "👎"),
+    ("fist", "✊"),
+    ("punch", "👊"),
+    ("clap", "👏"),
+    ("raised_hands", "🙌"),
+    ("open_hands", "👐"),
+    ("handshake", "🤝"),
+    ("pray", "🙏"),
+    ("muscle", "💪"),
+    ("eyes", "👀"),
+    ("eye", "👁"),
+    ("brain", "🧠"),
+    ("facepalm", "🤦"),
+    ("shrug", "🤷"),
+    ("heart", "❤️"),
+    ("orange_heart", "🧡"),
+    ("yellow_heart", "💛"),
+    ("green_heart", "💚"),
+    ("blue_heart", "💙"),
+    ("purple_heart", "💜"),
+    ("black_heart", "🖤"),
+    ("broken_heart", "💔"),
+    ("two_hearts", "💕"),
+    ("sparkling_heart", "💖"),
+    ("100", "💯"),
+    ("boom", "💥"),
+    ("fire", "🔥"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("zap", "⚡"),
+    ("tada", "🎉"),
+    ("confetti_ball", "🎊"),
+    ("gift", "🎁"),
+    ("trophy", "🏆"),
+    ("medal", "🏅"),
+    ("white_check_mark", "✅"),
+    ("heavy_check_mark", "✔️"),
+    ("x", "❌"),
+    ("warning", "⚠️"),
+    ("no_entry", "⛔"),
+    ("question", "❓"),
+    ("exclamation", "❗"),
+    ("bulb", "💡"),
+    ("memo", "📝"),
+    ("pushpin", "📌"),
+    ("link", "🔗"),
+    ("lock", "🔒"),
+    ("key", "🔑"),
+    ("hammer", "🔨"),
+    ("wrench", "🔧"),
+    ("gear", "⚙️"),
+    ("bug", "🐛"),
+    ("rocket", "🚀"),
+    ("hourglass", "⌛"),
+    ("alarm_clock", "⏰"),
+    ("calendar", "📅"),
+    ("computer", "💻"),
+    ("keyboard", "⌨"),
+    ("phone", "☎️"),
+    ("email", "✉️"),
+    ("package", "📦"),
+    ("money_with_wings", "💸"),
+    ("chart_with_upwards_trend", "📈"),
+    ("chart_with_downwards_trend", "📉"),
+    ("coffee", "☕"),
+    ("tea", "🍵"),
+    ("beer", "🍺"),
+    ("beers", "🍻"),
+    ("wine_glass", "🍷"),
+    ("pizza", "🍕"),
+    ("hamburger", "🍔"),
+    ("taco", "🌮"),
+    ("cake", "🍰"),
+    ("cookie", "🍪"),
+    ("popcorn", "🍿"),
+    ("apple", "🍎"),
+    ("crab", "🦀"),
+    ("dog", "🐶"),
+    ("cat", "🐱"),
+    ("fox", "🦊"),
+    ("bear", "🐻"),
+    ("panda_face", "🐼"),
+    ("penguin", "🐧"),
+    ("frog", "🐸"),
+    ("snake", "🐍"),
+    ("turtle", "🐢"),
+    ("sun", "☀️"),
+    ("cloud", "☁️"),
+    ("rainbow", "🌈"),
+    ("snowflake", "❄️"),
+    ("ocean", "🌊"),
+    ("earth_americas", "🌎"),
+    ("new_moon", "🌑"),
+    ("full_moon", "🌕"),
+    ("musical_note", "🎵"),
+    ("headphones", "🎧"),
+    ("video_game", "🎮"),
+    ("game_die", "🎲"),
+    ("soccer", "⚽"),
+    ("basketball", "🏀"),
+    ("zzz", "💤"),
+    ("speech_balloon", "💬"),
+    ("thought_balloon", "💭"),
+    ("arrow_up", "⬆️"),
+    ("arrow_down", "⬇️"),
+    ("arrow_left", "⬅️"),
+    ("arrow_right", "➡️"),
+    ("recycle", "♻️"),
+    ("red_circle", "🔴"),
+    ("green_circle", "🟢"),
+    ("blue_circle", "🔵"),
+];
+
+lazy_static! {
+    static ref BY_SHORTCODE: HashMap<&'static str, &'static str> = EMOJI.iter().cloned().collect();
+    static ref BY_EMOJI: HashMap<&'static str, &'static str> = {
+        let mut by_emoji = HashMap::new();
+        for (shortcode, emoji) in EMOJI.iter().rev() {
+            by_emoji.insert(*emoji, *shortcode);
+            // Variation selectors are often left off
+            by_emoji.insert(emoji.trim_end_matches('\u{FE0F}'), *shortcode);
+        }
+        by_emoji
+    };
+    /// Length in bytes of the longest emoji in the table
+    static ref LONGEST_EMOJI: usize = EMOJI.iter().map(|(_, emoji)| emoji.len()).max().unwrap_or(0);
+}
+
+/// The unicode emoji for a shortcode, without colons
+pub fn emoji_for_shortcode(shortcode: &str) -> Option<&'static str> {
+    BY_SHORTCODE.get(shortcode).copied()
+}
+
+/// Every known shortcode, without colons
+pub fn shortcodes() -> impl Iterator<Item = &'static str> {
+    EMOJI.iter().map(|(shortcode, _)| *shortcode)
+}
+
+/// Replace every known unicode emoji in `text` with its `:shortcode:`
+pub fn replace_emoji_with_shortcodes(text: &str) -> String {
+    let mut out = String::new();
+    let mut pos = 0;
+
+    'outer: while pos < text.len() {
+        // Prefer the longest match so that emoji followed by a variation selector are replaced whole
+        let max_len = (*LONGEST_EMOJI).min(text.len() - pos);
+        for len in (1..=max_len).rev() {
+            if let Some(shortcode) = text
+                .get(pos..pos + len)
+                .and_then(|emoji| BY_EMOJI.get(emoji))
+            {
+                out.push(':');
+                out.push_str(shortcode);
+                out.push(':');
+                pos += len;
+                continue 'outer;
+            }
+        }
+
+        let c = text[pos..].chars().next().unwrap_or_default();
+        out.push(c);
+        pos += c.len_utf8();
+    }
+
+    out
+}
+
+/// Replace every `:shortcode:` in `text` using `lookup`, leaving unknown shortcodes as they are
+///
+/// Custom emoji that are already written as `<:name:id>` are left alone
+pub fn replace_shortcodes(text: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find(&[':', '<'][..]) {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if rest.starts_with('<') {
+            let len = custom_emoji_len(rest).unwrap_or(1);
+            out.push_str(&rest[..len]);
+            rest = &rest[len..];
+            continue;
+        }
+
+        let replacement = rest[1..].find(':').and_then(|end| {
+            let name = &rest[1..=end];
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return None;
+            }
+            Some((lookup(name)?, end + 2))
+        });
+        match replacement {
+            Some((replacement, len)) => {
+                out.push_str(&replacement);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push(':');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+
+    out
+}
+
+/// The length of the `<:name:id>` or `<a:name:id>` custom emoji `text` starts with
+fn custom_emoji_len(text: &str) -> Option<usize> {
+    let end = text.find('>')?;
+    let inner = &text[1..end];
+    let inner = inner.strip_prefix('a').unwrap_or(inner);
+    let mut parts = inner.split(':');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(""), Some(name), Some(id), None)
+            if !name.is_empty()
+                && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+                && !id.is_empty()
+                && id.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            Some(end + 1)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replace(text: &str) -> String {
+        replace_shortcodes(text, |name| match name {
+            "fire" => Some("\u{1f525}".to_owned()),
+            "blob" => Some("<:blob:123>".to_owned()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn known_shortcodes() {
+        assert_eq!(replace(":fire: :blob:"), "\u{1f525} <:blob:123>");
+        assert_eq!(replace(":unknown: :fire"), ":unknown: :fire");
+        assert_eq!(replace("a < b :fire:"), "a < b \u{1f525}");
+    }
+
+    #[test]
+    fn custom_emoji_are_kept() {
+        assert_eq!(replace("<:blob:123>"), "<:blob:123>");
+        assert_eq!(replace("<:fire:123> :fire:"), "<:fire:123> \u{1f525}");
+        assert_eq!(replace("<a:fire:123>"), "<a:fire:123>");
+        assert_eq!(replace("<:fire:abc>"), "<\u{1f525}abc>");
+    }
+}
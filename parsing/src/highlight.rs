@@ -0,0 +1,345 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TokenKind {
+    Plain,
+    Keyword,
+    String,
+    Comment,
+    Number,
+    Added,
+    Removed,
+}
+
+struct Language {
+    keywords: &'static [&'static str],
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+}
+
+const RUST: Language = Language {
+    keywords: &[
+        "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+        "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+        "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait",
+        "true", "type", "unsafe", "use", "where", "while",
+    ],
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    // Single quotes are also used for lifetimes, so only highlight double quoted strings
+    quotes: &['"'],
+};
+
+const PYTHON: Language = Language {
+    keywords: &[
+        "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+        "elif", "else", "except", "False", "finally", "for", "from", "global", "if", "import",
+        "in", "is", "lambda", "None", "nonlocal", "not", "or", "pass", "raise", "return", "True",
+        "try", "while", "with", "yield",
+    ],
+    line_comments: &["#"],
+    block_comment: None,
+    quotes: &['"', '\''],
+};
+
+const JAVASCRIPT: Language = Language {
+    keywords: &[
+        "async",
+        "await",
+        "break",
+        "case",
+        "catch",
+        "class",
+        "const",
+        "continue",
+        "default",
+        "delete",
+        "do",
+        "else",
+        "enum",
+        "export",
+        "extends",
+        "false",
+        "finally",
+        "for",
+        "function",
+        "if",
+        "implements",
+        "import",
+        "in",
+        "instanceof",
+        "interface",
+        "let",
+        "new",
+        "null",
+        "of",
+        "private",
+        "protected",
+        "public",
+        "return",
+        "static",
+        "super",
+        "switch",
+        "this",
+        "throw",
+        "true",
+        "try",
+        "type",
+        "typeof",
+        "undefined",
+        "var",
+        "void",
+        "while",
+        "yield",
+    ],
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &['"', '\'', '`'],
+};
+
+const JSON: Language = Language {
+    keywords: &["true", "false", "null"],
+    line_comments: &[],
+    block_comment: None,
+    quotes: &['"'],
+};
+
+const SHELL: Language = Language {
+    keywords: &[
+        "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+        "in", "local", "return", "then", "until", "while",
+    ],
+    line_comments: &["#"],
+    block_comment: None,
+    quotes: &['"', '\''],
+};
+
+const TOML: Language = Language {
+    keywords: &["true", "false"],
+    line_comments: &["#"],
+    block_comment: None,
+    quotes: &['"', '\''],
+};
+
+fn language(name: &str) -> Option<&'static Language> {
+    match name.to_lowercase().as_str() {
+        "rust" | "rs" => Some(&RUST),
+        "python" | "py" => Some(&PYTHON),
+        "javascript" | "js" | "jsx" | "typescript" | "ts" | "tsx" => Some(&JAVASCRIPT),
+        "json" => Some(&JSON),
+        "sh" | "bash" | "shell" | "zsh" => Some(&SHELL),
+        "toml" => Some(&TOML),
+        _ => None,
+    }
+}
+
+/// Split code into highlighted tokens, or `None` if the language isn't supported
+///
+/// The tokens cover the whole of `code`, so joining them gives back the original text
+pub fn highlight<'a>(language_name: &str, code: &'a str) -> Option<Vec<(TokenKind, &'a str)>> {
+    match language_name.to_lowercase().as_str() {
+        "diff" | "patch" => Some(highlight_diff(code)),
+        _ => language(language_name).map(|language| tokenize(language, code)),
+    }
+}
+
+fn highlight_diff(code: &str) -> Vec<(TokenKind, &str)> {
+    lines_with_endings(code)
+        .into_iter()
+        .map(|line| {
+            let kind =
+                if line.starts_with("+++") || line.starts_with("---") || line.starts_with("@@") {
+                    TokenKind::Keyword
+                } else if line.starts_with('+') {
+                    TokenKind::Added
+                } else if line.starts_with('-') {
+                    TokenKind::Removed
+                } else {
+                    TokenKind::Plain
+                };
+            (kind, line)
+        })
+        .collect()
+}
+
+/// Split text into lines, keeping the newlines
+fn lines_with_endings(text: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, _) in text.match_indices('\n') {
+        lines.push(&text[start..=i]);
+        start = i + 1;
+    }
+    if start < text.len() {
+        lines.push(&text[start..]);
+    }
+    lines
+}
+
+fn tokenize<'a>(language: &Language, code: &'a str) -> Vec<(TokenKind, &'a str)> {
+    let mut tokens = Vec::new();
+    let mut plain_start = 0;
+    let mut pos = 0;
+
+    while pos < code.len() {
+        let rest = &code[pos..];
+        let c = rest.chars().next().unwrap_or_default();
+
+        let token = if language.line_comments.iter().any(|p| rest.starts_with(p)) {
+            let len = rest.find('\n').unwrap_or(rest.len());
+            Some((TokenKind::Comment, len))
+        } else if let Some((start, end)) = language
+            .block_comment
+            .filter(|(start, _)| rest.starts_with(start))
+        {
+            let len = rest[start.len()..]
+                .find(end)
+                .map(|i| i + start.len() + end.len())
+                .unwrap_or(rest.len());
+            Some((TokenKind::Comment, len))
+        } else if language.quotes.contains(&c) {
+            Some((TokenKind::String, string_len(rest, c)))
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            Some((TokenKind::Number, len))
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            if language.keywords.contains(&&rest[..len]) {
+                Some((TokenKind::Keyword, len))
+            } else {
+                // Identifiers are skipped whole so that numbers inside them aren't highlighted
+                pos += len;
+                continue;
+            }
+        } else {
+            None
+        };
+
+        match token {
+            Some((kind, len)) => {
+                if plain_start < pos {
+                    tokens.push((TokenKind::Plain, &code[plain_start..pos]));
+                }
+                tokens.push((kind, &code[pos..pos + len]));
+                pos += len;
+                plain_start = pos;
+            }
+            None => pos += c.len_utf8(),
+        }
+    }
+    if plain_start < code.len() {
+        tokens.push((TokenKind::Plain, &code[plain_start..]));
+    }
+
+    tokens
+}
+
+/// Length of the string literal at the start of `text`, up to and including the closing quote
+fn string_len(text: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            // Only backticks can span lines
+            '\n' if quote != '`' => return i,
+            c if c == quote => return i + c.len_utf8(),
+            _ => {}
+        }
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joined(tokens: &[(TokenKind, &str)]) -> String {
+        tokens.iter().map(|(_, text)| *text).collect()
+    }
+
+    #[test]
+    fn tokens_cover_input() {
+        let samples = [
+            (
+                "rust",
+                "fn main() {\n    // hi\n    let x = \"a\\\"b\"; /* c */ 1.5\n}",
+            ),
+            ("python", "def f(x):\n    return 'a' # comment\n"),
+            ("js", "const a = `multi\nline`; // done"),
+            ("json", "{\"a\": [1, true, null]}"),
+            ("sh", "if [ -f x ]; then echo \"hi\"; fi # end"),
+            ("toml", "[package]\nname = \"x\" # comment"),
+            ("diff", "--- a\n+++ b\n@@ -1 +1 @@\n-old\n+new\n same"),
+            ("rust", "héllo wörld \"ünïcode\" 42"),
+        ];
+        for (language, code) in &samples {
+            let tokens = highlight(language, code).unwrap();
+            assert_eq!(joined(&tokens), *code, "{}", language);
+        }
+    }
+
+    #[test]
+    fn keywords_strings_and_comments() {
+        let tokens = highlight("rust", "let s = \"fn\"; // let").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenKind::Keyword, "let"),
+                (TokenKind::Plain, " s = "),
+                (TokenKind::String, "\"fn\""),
+                (TokenKind::Plain, "; "),
+                (TokenKind::Comment, "// let"),
+            ]
+        );
+    }
+
+    #[test]
+    fn numbers_inside_identifiers_are_plain() {
+        let tokens = highlight("python", "x1 = 2").unwrap();
+        assert_eq!(
+            tokens,
+            vec![(TokenKind::Plain, "x1 = "), (TokenKind::Number, "2")]
+        );
+    }
+
+    #[test]
+    fn unterminated_string_ends_at_line_end() {
+        let code = "x = \"abc\ny = 1";
+        let tokens = highlight("python", code).unwrap();
+        assert_eq!(joined(&tokens), code);
+        assert!(tokens.contains(&(TokenKind::String, "\"abc")));
+        assert!(tokens.contains(&(TokenKind::Number, "1")));
+    }
+
+    #[test]
+    fn unterminated_string_at_end_of_input() {
+        let code = "echo 'abc";
+        let tokens = highlight("sh", code).unwrap();
+        assert_eq!(joined(&tokens), code);
+        assert_eq!(tokens.last(), Some(&(TokenKind::String, "'abc")));
+    }
+
+    #[test]
+    fn unterminated_block_comment() {
+        let code = "a /* b\nc";
+        let tokens = highlight("js", code).unwrap();
+        assert_eq!(joined(&tokens), code);
+        assert_eq!(tokens.last(), Some(&(TokenKind::Comment, "/* b\nc")));
+    }
+
+    #[test]
+    fn unknown_language() {
+        assert_eq!(highlight("brainfuck", "+++"), None);
+        assert_eq!(highlight("", "code"), None);
+    }
+
+    #[test]
+    fn language_names_are_case_insensitive() {
+        assert!(highlight("Rust", "fn").is_some());
+        assert!(highlight("DIFF", "+a").is_some());
+    }
+}
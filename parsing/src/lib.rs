@@ -1,7 +1,13 @@
+pub mod diff;
+pub mod emoji;
+pub mod highlight;
+
 use lazy_static::lazy_static;
 use simple_ast::regex::Regex;
 pub use simple_ast::MarkdownNode;
 use simple_ast::{Parser, Rule, Styled};
+use std::rc::Rc;
+use std::sync::RwLock;
 
 pub fn parse_markdown(str: &str) -> Styled<MarkdownNode> {
     use simple_ast::markdown_rules::*;
@@ -22,43 +28,1118 @@ pub fn parse_markdown(str: &str) -> Styled<MarkdownNode> {
     Parser::with_rules(rules).parse(str)
 }
 
+/// A piece of a message, either markdown styling or Discord's own syntax
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Text(String),
+    Bold(Vec<Node>),
+    Italic(Vec<Node>),
+    Underline(Vec<Node>),
+    Strikethrough(Vec<Node>),
+    Spoiler(Vec<Node>),
+    BlockQuote(Vec<Node>),
+    SingleBlockQuote(Vec<Node>),
+    InlineCode(String),
+    /// A code block and its language
+    Code(String, String),
+    /// `<@id>` or `<@!id>`
+    UserMention(u64),
+    /// `<@&id>`
+    RoleMention(u64),
+    /// `<#id>`
+    ChannelMention(u64),
+    /// `<:name:id>` or `<a:name:id>`
+    Emoji {
+        name: String,
+        id: u64,
+        animated: bool,
+    },
+    /// `<t:unix>` or `<t:unix:style>`
+    Timestamp {
+        unix: i64,
+        style: Option<char>,
+    },
+    /// `[text](url)`
+    MaskedLink {
+        text: String,
+        url: String,
+    },
+    /// A bare url, or `<url>` for a link without an embed
+    Url(String),
+}
+
+/// Parse a message into markdown styles and the Discord syntax within them
+///
+/// Links and Discord's tags are swapped for placeholders before the markdown is parsed, so that
+/// the `_`s and `*`s urls can contain aren't taken for styling
+pub fn parse_message(text: &str) -> Vec<Node> {
+    let (protected, tokens) = protect_tokens(text);
+    convert_styles(&parse_markdown(&protected).0, &tokens)
+}
+
+/// Placeholders come from the private use area. Private use characters already in the message
+/// are escaped as tokens of their own, so they can't be mistaken for placeholders
+const PLACEHOLDER_START: u32 = 0xE000;
+const PLACEHOLDER_END: u32 = 0xF8FF;
+
+/// Discord syntax taken out of a message before its markdown is parsed
+struct Token<'a> {
+    /// The text the syntax was written as
+    raw: &'a str,
+    /// `None` for a private use character the message contained, which is kept as text
+    node: Option<DiscordNode<'a>>,
+}
+
+fn is_placeholder(c: char) -> bool {
+    (PLACEHOLDER_START..=PLACEHOLDER_END).contains(&(c as u32))
+}
+
+/// Replace each piece of Discord syntax with a placeholder character
+fn protect_tokens(text: &str) -> (String, Vec<Token<'_>>) {
+    let mut protected = String::new();
+    let mut tokens = Vec::new();
+    // There are more placeholders than a Discord message has characters, so they only run out
+    // for longer text, where what's left is kept as it was written
+    let mut push_token = |protected: &mut String, raw, node| {
+        let placeholder = std::char::from_u32(PLACEHOLDER_START + tokens.len() as u32)
+            .filter(|c| is_placeholder(*c));
+        match placeholder {
+            Some(placeholder) => {
+                protected.push(placeholder);
+                tokens.push(Token { raw, node });
+            }
+            None => protected.push_str(raw),
+        }
+    };
+    for (raw, node) in parse_discord_nodes(text) {
+        match node {
+            DiscordNode::Text(text) => {
+                let mut start = 0;
+                for (i, c) in text.char_indices().filter(|(_, c)| is_placeholder(*c)) {
+                    protected.push_str(&text[start..i]);
+                    start = i + c.len_utf8();
+                    push_token(&mut protected, &text[i..start], None);
+                }
+                protected.push_str(&text[start..]);
+            }
+            node => push_token(&mut protected, raw, Some(node)),
+        }
+    }
+    (protected, tokens)
+}
+
+fn placeholder_token<'t, 'a>(c: char, tokens: &'t [Token<'a>]) -> Option<&'t Token<'a>> {
+    (c as u32)
+        .checked_sub(PLACEHOLDER_START)
+        .and_then(|i| tokens.get(i as usize))
+}
+
+/// Split text into plain text and the nodes its placeholders stand for
+fn expand_tokens(text: &str, tokens: &[Token]) -> Vec<Node> {
+    let mut nodes = Vec::new();
+    let mut plain = String::new();
+    for c in text.chars() {
+        match placeholder_token(c, tokens) {
+            Some(Token {
+                node: Some(node), ..
+            }) => {
+                if !plain.is_empty() {
+                    nodes.push(Node::Text(std::mem::take(&mut plain)));
+                }
+                nodes.push(Node::from(node.clone()));
+            }
+            Some(Token { raw, node: None }) => plain.push_str(raw),
+            None => plain.push(c),
+        }
+    }
+    if !plain.is_empty() {
+        nodes.push(Node::Text(plain));
+    }
+    nodes
+}
+
+/// Put the original text back in place of placeholders, for code where nothing is parsed
+fn restore_tokens(text: &str, tokens: &[Token]) -> String {
+    text.chars()
+        .map(|c| match placeholder_token(c, tokens) {
+            Some(token) => token.raw.to_owned(),
+            None => c.to_string(),
+        })
+        .collect()
+}
+
+fn convert_styles(styles: &[Rc<RwLock<MarkdownNode>>], tokens: &[Token]) -> Vec<Node> {
+    let mut nodes = Vec::new();
+    // The markdown parser can split text on punctuation, so join it back together
+    let mut text = String::new();
+    for style in styles {
+        use MarkdownNode::*;
+        let node = match &*style.read().unwrap() {
+            Text(string) => {
+                text.push_str(string);
+                continue;
+            }
+            Bold(styles) => Node::Bold(convert_styles(styles, tokens)),
+            Italic(styles) => Node::Italic(convert_styles(styles, tokens)),
+            Underline(styles) => Node::Underline(convert_styles(styles, tokens)),
+            Strikethrough(styles) => Node::Strikethrough(convert_styles(styles, tokens)),
+            Spoiler(styles) => Node::Spoiler(convert_styles(styles, tokens)),
+            BlockQuote(styles) => Node::BlockQuote(convert_styles(styles, tokens)),
+            SingleBlockQuote(styles) => Node::SingleBlockQuote(convert_styles(styles, tokens)),
+            InlineCode(code) => Node::InlineCode(restore_tokens(code, tokens)),
+            Code(language, code) => Node::Code(
+                restore_tokens(language, tokens),
+                restore_tokens(code, tokens),
+            ),
+        };
+        nodes.extend(expand_tokens(&text, tokens));
+        text.clear();
+        nodes.push(node);
+    }
+    nodes.extend(expand_tokens(&text, tokens));
+    nodes
+}
+
+impl From<DiscordNode<'_>> for Node {
+    fn from(node: DiscordNode) -> Node {
+        match node {
+            DiscordNode::Text(text) => Node::Text(text.to_owned()),
+            DiscordNode::UserMention(id) => Node::UserMention(id),
+            DiscordNode::RoleMention(id) => Node::RoleMention(id),
+            DiscordNode::ChannelMention(id) => Node::ChannelMention(id),
+            DiscordNode::Emoji { name, id, animated } => Node::Emoji {
+                name: name.to_owned(),
+                id,
+                animated,
+            },
+            DiscordNode::Timestamp { unix, style } => Node::Timestamp { unix, style },
+            DiscordNode::MaskedLink { text, url } => Node::MaskedLink {
+                text: text.to_owned(),
+                url: url.to_owned(),
+            },
+            DiscordNode::Url(url) => Node::Url(url.to_owned()),
+        }
+    }
+}
+
+/// Discord-specific syntax that can appear within text
+#[derive(Debug, Clone, PartialEq)]
+enum DiscordNode<'a> {
+    Text(&'a str),
+    /// `<@id>` or `<@!id>`
+    UserMention(u64),
+    /// `<@&id>`
+    RoleMention(u64),
+    /// `<#id>`
+    ChannelMention(u64),
+    /// `<:name:id>` or `<a:name:id>`
+    Emoji {
+        name: &'a str,
+        id: u64,
+        animated: bool,
+    },
+    /// `<t:unix>` or `<t:unix:style>`
+    Timestamp {
+        unix: i64,
+        style: Option<char>,
+    },
+    /// `[text](url)`
+    MaskedLink {
+        text: &'a str,
+        url: &'a str,
+    },
+    /// A bare url, or `<url>` for a link without an embed
+    Url(&'a str),
+}
+
+/// Split text into plain text and the Discord-specific syntax the markdown parser doesn't know,
+/// along with the text each node was parsed from
+fn parse_discord_nodes(text: &str) -> Vec<(&str, DiscordNode<'_>)> {
+    let mut nodes = Vec::new();
+    let mut text_start = 0;
+    let mut pos = 0;
+
+    while let Some(open) = text[pos..]
+        .find(|c| c == '<' || c == '[' || c == 'h')
+        .map(|i| i + pos)
+    {
+        let rest = &text[open..];
+        let node = if rest.starts_with('<') {
+            rest.find('>')
+                .and_then(|close| Some((parse_discord_tag(&rest[1..close])?, close + 1)))
+        } else if rest.starts_with('[') {
+            parse_masked_link(rest).map(|(node, close)| (node, close + 1))
+        } else {
+            bare_url_len(text, open).map(|len| (DiscordNode::Url(&rest[..len]), len))
+        };
+        match node {
+            Some((node, len)) => {
+                if text_start < open {
+                    let plain = &text[text_start..open];
+                    nodes.push((plain, DiscordNode::Text(plain)));
+                }
+                nodes.push((&rest[..len], node));
+                pos = open + len;
+                text_start = pos;
+            }
+            None => pos = open + 1,
+        }
+    }
+    if text_start < text.len() {
+        let plain = &text[text_start..];
+        nodes.push((plain, DiscordNode::Text(plain)));
+    }
+
+    nodes
+}
+
+/// The length of the bare url at `start`, if there is one
+///
+/// Like Discord, urls are only found at the start of a word, and punctuation at their end is
+/// left to the sentence around them
+fn bare_url_len(text: &str, start: usize) -> Option<usize> {
+    let rest = &text[start..];
+    if !rest.starts_with("https://") && !rest.starts_with("http://") {
+        return None;
+    }
+    let starts_word = text[..start]
+        .chars()
+        .next_back()
+        .map_or(true, |c| c.is_whitespace() || "([{*_~|>".contains(c));
+    if !starts_word {
+        return None;
+    }
+
+    let mut len = rest
+        .find(|c: char| c.is_whitespace() || c == '<')
+        .unwrap_or(rest.len());
+    while let Some(last) = rest[..len].chars().next_back() {
+        let url = &rest[..len];
+        let unbalanced = last == ')' && url.matches('(').count() < url.matches(')').count();
+        if unbalanced || ".,:;!?'\"]*_~|".contains(last) {
+            len -= last.len_utf8();
+        } else {
+            break;
+        }
+    }
+
+    let scheme_len = rest.find("://")? + 3;
+    if len > scheme_len {
+        Some(len)
+    } else {
+        None
+    }
+}
+
+fn is_url(text: &str) -> bool {
+    (text.starts_with("https://") || text.starts_with("http://"))
+        && !text.contains(char::is_whitespace)
+}
+
+/// Parse a `[text](url)` link at the start of `text`, along with the index of its closing `)`
+fn parse_masked_link(text: &str) -> Option<(DiscordNode<'_>, usize)> {
+    let text_end = text.find("](")?;
+    let url_end = text_end + 2 + text[text_end + 2..].find(')')?;
+    let (link_text, url) = (&text[1..text_end], &text[text_end + 2..url_end]);
+    // Discord also allows the url to be wrapped in <> to suppress its embed
+    let url = if url.starts_with('<') && url.ends_with('>') {
+        &url[1..url.len() - 1]
+    } else {
+        url
+    };
+
+    if link_text.trim().is_empty()
+        || link_text.contains(|c: char| c == '\n' || c == '[')
+        || !is_url(url)
+    {
+        return None;
+    }
+    Some((
+        DiscordNode::MaskedLink {
+            text: link_text,
+            url,
+        },
+        url_end,
+    ))
+}
+
+/// Parse the inside of a `<...>` tag
+fn parse_discord_tag(tag: &str) -> Option<DiscordNode<'_>> {
+    if is_url(tag) {
+        return Some(DiscordNode::Url(tag));
+    }
+    if tag.starts_with("@&") {
+        return tag[2..].parse().ok().map(DiscordNode::RoleMention);
+    }
+    if tag.starts_with("@!") {
+        return tag[2..].parse().ok().map(DiscordNode::UserMention);
+    }
+    if tag.starts_with('@') {
+        return tag[1..].parse().ok().map(DiscordNode::UserMention);
+    }
+    if tag.starts_with('#') {
+        return tag[1..].parse().ok().map(DiscordNode::ChannelMention);
+    }
+    if tag.starts_with("t:") {
+        let mut parts = tag[2..].splitn(2, ':');
+        let unix = parts.next()?.parse().ok()?;
+        let style = match parts.next() {
+            Some(style) if style.len() == 1 && "tTdDfFR".contains(style) => style.chars().next(),
+            Some(_) => return None,
+            None => None,
+        };
+        return Some(DiscordNode::Timestamp { unix, style });
+    }
+
+    let (animated, emoji) = if tag.starts_with("a:") {
+        (true, &tag[2..])
+    } else if tag.starts_with(':') {
+        (false, &tag[1..])
+    } else {
+        return None;
+    };
+    let mut parts = emoji.splitn(2, ':');
+    let name = parts.next().filter(|name| !name.is_empty())?;
+    let id = parts.next()?.parse().ok()?;
+    Some(DiscordNode::Emoji { name, id, animated })
+}
+
 pub fn weechat_arg_strip(str: &str) -> String {
     str.trim().replace(' ', "_")
 }
 
 lazy_static! {
-    static ref LINE_SUB_REGEX: Regex =
-        Regex::new(r"^(\d)?s/(.*?(?<!\\))/(.*?(?<!\\))(?:/|$)(\w+)?").unwrap();
+    static ref LINE_REACT_REGEX: Regex = Regex::new(r"^([+-]):([^:\s]+):$").unwrap();
+}
+
+/// Characters that can separate the parts of a substitution, like the `/`s in `s/old/new/`
+const SUB_DELIMITERS: &str = "/|,:!@%~";
+
+/// Flags a substitution accepts after its last delimiter
+const SUB_FLAGS: &str = "gi";
+
+/// The message a line edit applies to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageTarget {
+    /// The nth most recent message, starting at 1
+    Line(usize),
+    /// A message id, written as `#id`
+    Id(u64),
+}
+
+impl std::fmt::Display for MessageTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MessageTarget::Line(line) => write!(f, "{}", line),
+            MessageTarget::Id(id) => write!(f, "#{}", id),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum LineEdit<'a> {
+    /// `s/old/new/flags`, where `old` is a regex
     Sub {
-        line: usize,
-        old: &'a str,
-        new: &'a str,
+        target: MessageTarget,
+        old: String,
+        new: String,
         options: Option<&'a str>,
+        /// The character used in place of `/`
+        delimiter: char,
     },
-    Delete {
-        line: usize,
+    /// `s///` or `Ndelete`
+    Delete { target: MessageTarget },
+    React {
+        target: MessageTarget,
+        emoji: &'a str,
+        remove: bool,
     },
 }
 
 pub fn parse_line_edit(input: &str) -> Option<LineEdit> {
-    let caps = LINE_SUB_REGEX.captures(input)?;
+    let (target, rest) = parse_message_target(input);
+
+    if let Some(caps) = LINE_REACT_REGEX.captures(rest) {
+        return Some(LineEdit::React {
+            target: target.unwrap_or(MessageTarget::Line(1)),
+            emoji: caps.at(2)?,
+            remove: caps.at(1) == Some("-"),
+        });
+    }
 
-    let line = caps.at(1).and_then(|l| l.parse().ok()).unwrap_or(1);
-    let old = caps.at(2)?;
-    let new = caps.at(3)?;
+    // A bare "delete" is far too likely to be a normal message
+    if rest == "delete" {
+        return target.map(|target| LineEdit::Delete { target });
+    }
 
+    let target = target.unwrap_or(MessageTarget::Line(1));
+    let (old, new, options, delimiter) = parse_substitution(rest)?;
     if old.is_empty() && new.is_empty() {
-        Some(LineEdit::Delete { line })
+        Some(LineEdit::Delete { target })
     } else {
         Some(LineEdit::Sub {
-            line,
+            target,
             old,
             new,
-            options: caps.at(4),
+            options,
+            delimiter,
         })
     }
 }
+
+/// Parse a command argument naming a message, either `n` for the nth most recent or `#id`
+pub fn parse_message_arg(arg: &str) -> Option<MessageTarget> {
+    match parse_message_target(arg) {
+        (Some(target), "") => Some(target),
+        _ => None,
+    }
+}
+
+/// Split a leading line number or `#id` from a line edit
+fn parse_message_target(input: &str) -> (Option<MessageTarget>, &str) {
+    let (is_id, digits_start) = if input.starts_with('#') {
+        (true, 1)
+    } else {
+        (false, 0)
+    };
+    let digits_end = input[digits_start..]
+        .find(|c: char| !c.is_ascii_digit())
+        .map(|i| i + digits_start)
+        .unwrap_or(input.len());
+
+    let digits = &input[digits_start..digits_end];
+    let target = if is_id {
+        digits.parse().ok().map(MessageTarget::Id)
+    } else {
+        digits.parse().ok().map(MessageTarget::Line)
+    };
+    match target {
+        Some(target) => (Some(target), &input[digits_end..]),
+        None => (None, input),
+    }
+}
+
+/// Parse `s/old/new/flags`, with any of `SUB_DELIMITERS` in place of `/`
+///
+/// All three delimiters are required, so that messages like `s, I think so` are sent as is
+fn parse_substitution(input: &str) -> Option<(String, String, Option<&str>, char)> {
+    let mut chars = input.chars();
+    if chars.next()? != 's' {
+        return None;
+    }
+    let delimiter = chars.next().filter(|c| SUB_DELIMITERS.contains(*c))?;
+    let body = &input[1 + delimiter.len_utf8()..];
+
+    let (old, new, options) = match split_unescaped(body, delimiter).as_slice() {
+        [old, new, options] if options.chars().all(|c| SUB_FLAGS.contains(c)) => {
+            (*old, *new, Some(*options).filter(|o| !o.is_empty()))
+        }
+        _ => return None,
+    };
+
+    Some((
+        unescape_delimiter(old, delimiter),
+        unescape_delimiter(new, delimiter),
+        options,
+        delimiter,
+    ))
+}
+
+/// Split on every `delimiter` that isn't escaped with a backslash
+fn split_unescaped(text: &str, delimiter: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == delimiter => {
+                parts.push(&text[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+fn unescape_delimiter(text: &str, delimiter: char) -> String {
+    text.replace(&format!("\\{}", delimiter), &delimiter.to_string())
+}
+
+enum Block<'a> {
+    Paragraph(Vec<&'a str>),
+    Code { fence: &'a str, lines: Vec<&'a str> },
+}
+
+impl<'a> Block<'a> {
+    fn text(&self) -> String {
+        match self {
+            Block::Paragraph(lines) => lines.join("\n"),
+            Block::Code { fence, lines } if lines.is_empty() => format!("{}\n```", fence),
+            Block::Code { fence, lines } => format!("{}\n{}\n```", fence, lines.join("\n")),
+        }
+    }
+
+    /// Split a block that is too long into chunks of at most `max_len` characters
+    fn split(&self, max_len: usize) -> Vec<String> {
+        match self {
+            Block::Paragraph(lines) => pack_lines(lines, max_len),
+            Block::Code { fence, lines } => {
+                // Leave room to close and reopen the fence in every chunk
+                let room = max_len.saturating_sub(char_len(fence) + "\n\n```".len());
+                pack_lines(lines, room.max(1))
+                    .into_iter()
+                    .map(|body| format!("{}\n{}\n```", fence, body))
+                    .collect()
+            }
+        }
+    }
+}
+
+fn char_len(str: &str) -> usize {
+    str.chars().count()
+}
+
+/// Split text into paragraphs and code blocks, along with whether they follow a blank line
+fn split_blocks(text: &str) -> Vec<(bool, Block<'_>)> {
+    let mut blocks = Vec::new();
+    let mut paragraph = Vec::new();
+    let mut code: Option<(&str, Vec<&str>)> = None;
+    let mut blank_before = false;
+
+    for line in text.lines() {
+        if let Some((fence, mut lines)) = code.take() {
+            let trimmed = line.trim_end();
+            if trimmed.ends_with("```") {
+                let rest = &trimmed[..trimmed.len() - 3];
+                if !rest.is_empty() {
+                    lines.push(rest);
+                }
+                blocks.push((blank_before, Block::Code { fence, lines }));
+                blank_before = false;
+            } else {
+                lines.push(line);
+                code = Some((fence, lines));
+            }
+            continue;
+        }
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            if !paragraph.is_empty() {
+                blocks.push((blank_before, Block::Paragraph(paragraph)));
+                paragraph = Vec::new();
+            }
+            blank_before = true;
+        } else if trimmed.starts_with("```") && !trimmed[3..].contains("```") {
+            if !paragraph.is_empty() {
+                blocks.push((blank_before, Block::Paragraph(paragraph)));
+                paragraph = Vec::new();
+                blank_before = false;
+            }
+            code = Some((trimmed, Vec::new()));
+        } else {
+            paragraph.push(line);
+        }
+    }
+
+    if !paragraph.is_empty() {
+        blocks.push((blank_before, Block::Paragraph(paragraph)));
+    }
+    // An unclosed code block is closed so that it renders the same in every chunk
+    if let Some((fence, lines)) = code {
+        blocks.push((blank_before, Block::Code { fence, lines }));
+    }
+
+    blocks
+}
+
+/// Join lines into as few chunks of at most `max_len` characters as possible
+fn pack_lines(lines: &[&str], max_len: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+
+    for piece in lines.iter().flat_map(|line| split_line(line, max_len)) {
+        if !current.is_empty() && char_len(&current) + 1 + char_len(&piece) > max_len {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(&piece);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+/// Split a single line into pieces of at most `max_len` characters, preferring whitespace
+fn split_line(line: &str, max_len: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut rest: Vec<char> = line.chars().collect();
+
+    while rest.len() > max_len {
+        let split_at = rest[..max_len]
+            .iter()
+            .rposition(|c| c.is_whitespace())
+            .filter(|&i| i > 0)
+            .unwrap_or(max_len);
+        pieces.push(rest[..split_at].iter().collect());
+        rest = rest[split_at..].to_vec();
+    }
+    pieces.push(rest.into_iter().collect());
+
+    pieces
+}
+
+/// Split a message into chunks of at most `max_len` characters
+///
+/// Messages are split between paragraphs and code blocks where possible, and code blocks that
+/// have to be split are closed and reopened so that every chunk renders correctly
+pub fn split_message(text: &str, max_len: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+
+    for (blank_before, block) in split_blocks(text) {
+        let block_text = block.text();
+        let separator = if blank_before { "\n\n" } else { "\n" };
+
+        if current.is_empty() && char_len(&block_text) <= max_len {
+            current = block_text;
+            continue;
+        }
+        if char_len(&current) + char_len(separator) + char_len(&block_text) <= max_len {
+            current.push_str(separator);
+            current.push_str(&block_text);
+            continue;
+        }
+
+        if !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if char_len(&block_text) <= max_len {
+            current = block_text;
+        } else {
+            let mut pieces = block.split(max_len);
+            current = pieces.pop().unwrap_or_default();
+            chunks.extend(pieces);
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> Node {
+        Node::Text(text.to_owned())
+    }
+
+    fn discord_nodes(input: &str) -> Vec<Node> {
+        parse_discord_nodes(input)
+            .into_iter()
+            .map(|(_, node)| Node::from(node))
+            .collect()
+    }
+
+    #[test]
+    fn mentions() {
+        assert_eq!(
+            discord_nodes("hi <@1>, <@!2> <@&3> and <#4>"),
+            vec![
+                text("hi "),
+                Node::UserMention(1),
+                text(", "),
+                Node::UserMention(2),
+                text(" "),
+                Node::RoleMention(3),
+                text(" and "),
+                Node::ChannelMention(4),
+            ]
+        );
+    }
+
+    #[test]
+    fn emoji_and_timestamps() {
+        assert_eq!(
+            discord_nodes("<:blob:5><a:party:6> <t:7> <t:8:R>"),
+            vec![
+                Node::Emoji {
+                    name: "blob".into(),
+                    id: 5,
+                    animated: false
+                },
+                Node::Emoji {
+                    name: "party".into(),
+                    id: 6,
+                    animated: true
+                },
+                text(" "),
+                Node::Timestamp {
+                    unix: 7,
+                    style: None
+                },
+                text(" "),
+                Node::Timestamp {
+                    unix: 8,
+                    style: Some('R')
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(
+            discord_nodes("[docs](https://a.b/c) <https://d.e>"),
+            vec![
+                Node::MaskedLink {
+                    text: "docs".into(),
+                    url: "https://a.b/c".into()
+                },
+                text(" "),
+                Node::Url("https://d.e".into()),
+            ]
+        );
+        assert_eq!(
+            discord_nodes("[docs](<https://a.b>)"),
+            vec![Node::MaskedLink {
+                text: "docs".into(),
+                url: "https://a.b".into()
+            }]
+        );
+        assert_eq!(
+            discord_nodes("[](https://a.b)"),
+            vec![text("[]("), Node::Url("https://a.b".into()), text(")")]
+        );
+    }
+
+    #[test]
+    fn bare_urls() {
+        assert_eq!(
+            discord_nodes("see https://a.b/c_d_e, or (http://f.g/h_(i))."),
+            vec![
+                text("see "),
+                Node::Url("https://a.b/c_d_e".into()),
+                text(", or ("),
+                Node::Url("http://f.g/h_(i)".into()),
+                text(")."),
+            ]
+        );
+        for input in &["xhttps://a.b", "https://", "http://.", "hi there"] {
+            assert_eq!(discord_nodes(input), vec![text(input)], "{}", input);
+        }
+    }
+
+    #[test]
+    fn tokens_round_trip() {
+        let input = "a https://b.c/_d_e <@1> [e](https://f.g/*h*) i";
+        let (protected, tokens) = protect_tokens(input);
+        assert_eq!(tokens.len(), 3);
+        assert!(!protected.contains(|c| c == '_' || c == '*'));
+        assert_eq!(restore_tokens(&protected, &tokens), input);
+        assert_eq!(
+            expand_tokens(&protected, &tokens),
+            vec![
+                text("a "),
+                Node::Url("https://b.c/_d_e".into()),
+                text(" "),
+                Node::UserMention(1),
+                text(" "),
+                Node::MaskedLink {
+                    text: "e".into(),
+                    url: "https://f.g/*h*".into()
+                },
+                text(" i"),
+            ]
+        );
+
+        let input = "\u{e000} https://a.b \u{e001}";
+        let (protected, tokens) = protect_tokens(input);
+        assert_eq!(tokens.len(), 3);
+        assert_eq!(restore_tokens(&protected, &tokens), input);
+        assert_eq!(
+            expand_tokens(&protected, &tokens),
+            vec![
+                text("\u{e000} "),
+                Node::Url("https://a.b".into()),
+                text(" \u{e001}"),
+            ]
+        );
+        assert_eq!(
+            parse_message("\u{e001} <@1>"),
+            vec![text("\u{e001} "), Node::UserMention(1)]
+        );
+    }
+
+    #[test]
+    fn invalid_tags_are_text() {
+        for input in &[
+            "<@abc>",
+            "<t:1:x>",
+            "a < b > c",
+            "<:name>",
+            "[a](not a url)",
+        ] {
+            assert_eq!(discord_nodes(input), vec![text(input)], "{}", input);
+        }
+    }
+
+    #[test]
+    fn discord_syntax_inside_markdown() {
+        assert_eq!(
+            parse_message("**hi <@1>**"),
+            vec![Node::Bold(vec![text("hi "), Node::UserMention(1)])]
+        );
+        assert_eq!(
+            parse_message("https://a.b/_c_d *d*"),
+            vec![
+                Node::Url("https://a.b/_c_d".into()),
+                text(" "),
+                Node::Italic(vec![text("d")]),
+            ]
+        );
+        assert_eq!(
+            parse_message("**https://a.b/c**"),
+            vec![Node::Bold(vec![Node::Url("https://a.b/c".into())])]
+        );
+        assert_eq!(
+            parse_message("`https://a.b/_c_`"),
+            vec![Node::InlineCode("https://a.b/_c_".into())]
+        );
+        assert_eq!(
+            parse_message("`<@1>`"),
+            vec![Node::InlineCode("<@1>".into())]
+        );
+    }
+
+    fn sub(input: &str) -> Option<(MessageTarget, String, String, Option<&str>, char)> {
+        match parse_line_edit(input)? {
+            LineEdit::Sub {
+                target,
+                old,
+                new,
+                options,
+                delimiter,
+            } => Some((target, old, new, options, delimiter)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn substitution() {
+        assert_eq!(
+            sub("s/foo/bar/"),
+            Some((
+                MessageTarget::Line(1),
+                "foo".into(),
+                "bar".into(),
+                None,
+                '/'
+            ))
+        );
+        assert_eq!(
+            sub("3s|a|b|gi"),
+            Some((
+                MessageTarget::Line(3),
+                "a".into(),
+                "b".into(),
+                Some("gi"),
+                '|'
+            ))
+        );
+        assert_eq!(
+            sub("#123s/a/b/"),
+            Some((MessageTarget::Id(123), "a".into(), "b".into(), None, '/'))
+        );
+    }
+
+    #[test]
+    fn substitution_delimiters() {
+        for delimiter in SUB_DELIMITERS.chars() {
+            let input = format!("s{0}a{0}b{0}g", delimiter);
+            assert_eq!(
+                sub(&input),
+                Some((
+                    MessageTarget::Line(1),
+                    "a".into(),
+                    "b".into(),
+                    Some("g"),
+                    delimiter
+                )),
+                "{}",
+                input
+            );
+        }
+        assert!(parse_line_edit("s#a#b#").is_none());
+    }
+
+    #[test]
+    fn substitution_escapes() {
+        assert_eq!(
+            sub(r"s/a\/b/c\/d/"),
+            Some((
+                MessageTarget::Line(1),
+                "a/b".into(),
+                "c/d".into(),
+                None,
+                '/'
+            ))
+        );
+        assert_eq!(
+            sub(r"s|a\|b|\1|"),
+            Some((
+                MessageTarget::Line(1),
+                "a|b".into(),
+                r"\1".into(),
+                None,
+                '|'
+            ))
+        );
+    }
+
+    #[test]
+    fn substitution_flags() {
+        assert!(sub("s/a/b/g").is_some());
+        assert!(sub("s/a/b/ig").is_some());
+        assert!(sub("s/a/b/x").is_none());
+        assert!(sub("s/a/b/ and then some").is_none());
+    }
+
+    fn delete_target(input: &str) -> Option<MessageTarget> {
+        match parse_line_edit(input)? {
+            LineEdit::Delete { target } => Some(target),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn delete() {
+        assert_eq!(delete_target("s///"), Some(MessageTarget::Line(1)));
+        assert_eq!(delete_target("2delete"), Some(MessageTarget::Line(2)));
+        assert_eq!(delete_target("#5s///"), Some(MessageTarget::Id(5)));
+        assert!(parse_line_edit("delete").is_none());
+    }
+
+    #[test]
+    fn messages_that_are_not_edits() {
+        for text in &[
+            "s!play foo!",
+            "s, I think so, yes",
+            "s/foo/bar",
+            "s: nothing",
+            "so/what/now/",
+            "s/a/b/c/d",
+            "sure",
+            "",
+        ] {
+            assert!(parse_line_edit(text).is_none(), "{}", text);
+        }
+    }
+
+    fn assert_fits(chunks: &[String], max_len: usize) {
+        for chunk in chunks {
+            assert!(!chunk.trim().is_empty());
+            assert!(
+                char_len(chunk) <= max_len,
+                "{} > {}",
+                char_len(chunk),
+                max_len
+            );
+        }
+    }
+
+    #[test]
+    fn split_at_limit() {
+        let text = "a".repeat(2000);
+        assert_eq!(split_message(&text, 2000), vec![text.clone()]);
+
+        let text = "a".repeat(2001);
+        let chunks = split_message(&text, 2000);
+        assert_fits(&chunks, 2000);
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn split_prefers_paragraphs_and_whitespace() {
+        let paragraph = "word ".repeat(300);
+        let text = format!("{}\n\n{}", paragraph.trim(), paragraph.trim());
+        let chunks = split_message(&text, 2000);
+        assert_eq!(chunks, vec![paragraph.trim(), paragraph.trim()]);
+
+        let chunks = split_message(&"word ".repeat(500), 2000);
+        assert_fits(&chunks, 2000);
+        for chunk in &chunks {
+            assert!(
+                chunk.trim().split(' ').all(|word| word == "word"),
+                "{}",
+                chunk
+            );
+        }
+    }
+
+    #[test]
+    fn split_code_blocks() {
+        let code = (0..200)
+            .map(|i| format!("let x{} = {};", i, i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let text = format!("before\n```rust\n{}\n```\nafter", code);
+        let chunks = split_message(&text, 2000);
+        assert!(chunks.len() > 1);
+        assert_fits(&chunks, 2000);
+
+        let code_chunks: Vec<_> = chunks.iter().filter(|c| c.contains("let x")).collect();
+        for chunk in &code_chunks {
+            let body = chunk.trim_start_matches("before\n");
+            assert!(body.starts_with("```rust\n"), "{}", chunk);
+            assert!(
+                chunk.trim_end_matches("\nafter").ends_with("\n```"),
+                "{}",
+                chunk
+            );
+        }
+        let lines: Vec<_> = code_chunks
+            .iter()
+            .flat_map(|chunk| chunk.lines())
+            .filter(|line| line.starts_with("let x"))
+            .collect();
+        assert_eq!(lines, code.lines().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn split_unclosed_code_block() {
+        let text = format!("```\n{}", "x\n".repeat(1500));
+        let chunks = split_message(&text, 2000);
+        assert_fits(&chunks, 2000);
+        for chunk in &chunks {
+            assert!(
+                chunk.starts_with("```\n") && chunk.ends_with("\n```"),
+                "{}",
+                chunk
+            );
+        }
+    }
+
+    #[test]
+    fn split_multibyte() {
+        let text = "é".repeat(2001);
+        let chunks = split_message(&text, 2000);
+        assert_eq!(chunks, vec!["é".repeat(2000), "é".to_owned()]);
+
+        let text = "🦀 ".repeat(1500);
+        let chunks = split_message(&text, 2000);
+        assert_fits(&chunks, 2000);
+        assert_eq!(chunks.concat().matches('🦀').count(), 1500);
+    }
+}
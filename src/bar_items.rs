@@ -8,6 +8,7 @@ pub struct BarHandles {
     _channel_name: BarItem<()>,
     _full_name: BarItem<()>,
     _typing_indicator: BarItem<()>,
+    _edit_indicator: BarItem<()>,
 }
 
 pub fn init(weechat: &Weechat) -> BarHandles {
@@ -87,10 +88,23 @@ pub fn init(weechat: &Weechat) -> BarHandles {
         None,
     );
 
+    let _edit_indicator = weechat.new_bar_item(
+        "discord_edit",
+        |_, _, buffer| {
+            if crate::hook::is_editing(&buffer) {
+                "[editing]".into()
+            } else {
+                "".into()
+            }
+        },
+        None,
+    );
+
     BarHandles {
         _guild_name,
         _channel_name,
         _full_name,
         _typing_indicator,
+        _edit_indicator,
     }
 }
@@ -1,10 +1,12 @@
 use crate::sync::on_main_blocking;
 use crate::utils::ChannelExt;
 use crate::{on_main, utils};
+use chrono::{DateTime, FixedOffset};
 use indexmap::IndexMap;
+use lazy_static::lazy_static;
 use serenity::cache::Cache;
 use serenity::{cache::CacheRwLock, model::prelude::*, prelude::*};
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
 use weechat::buffer::HotlistPriority;
 use weechat::{Buffer, ConfigOption, NickArgs, Weechat};
@@ -12,6 +14,24 @@ use weechat::{Buffer, ConfigOption, NickArgs, Weechat};
 const OFFLINE_GROUP_NAME: &str = "99999|Offline";
 const ONLINE_GROUP_NAME: &str = "99998|Online";
 const BOT_GROUP_NAME: &str = "99999|Bot";
+/// Sorts before every other group, so that it is clear the nicklist is incomplete
+const MORE_MEMBERS_GROUP_PREFIX: &str = "00000|";
+/// Number of members added to the nicklists at a time, so the main thread isn't held up
+const MEMBER_BATCH_SIZE: usize = 1000;
+
+/// Members of a guild shown in its channels' nicklists
+#[derive(Default)]
+struct GuildMembers {
+    members: Vec<Member>,
+    ids: HashSet<UserId>,
+    /// Name of the "+N more" group in the nicklists, if every member isn't shown
+    more_group: Option<String>,
+}
+
+lazy_static! {
+    /// Members loaded for each guild, shared by the buffers of all of its channels
+    static ref GUILD_MEMBERS: Mutex<HashMap<GuildId, GuildMembers>> = Mutex::new(HashMap::new());
+}
 
 pub fn create_buffers(ready_data: &Ready) {
     let ctx = match crate::discord::get_ctx() {
@@ -430,9 +450,12 @@ pub fn load_history(buffer: &weechat::Buffer, completion_sender: crossbeam_chann
         Ok(v) => ChannelId(v),
         Err(_) => return,
     };
-    buffer.clear();
     buffer.set_localvar("loaded_history", "true");
 
+    let history_size = crate::upgrade_plugin(&buffer.get_weechat())
+        .config
+        .history_size
+        .value() as u64;
     let sealed_buffer = buffer.seal();
 
     std::thread::spawn(move || {
@@ -441,7 +464,8 @@ pub fn load_history(buffer: &weechat::Buffer, completion_sender: crossbeam_chann
             _ => return,
         };
 
-        if let Ok(msgs) = channel.messages(ctx, |retriever| retriever.limit(25)) {
+        let query = format!("?limit={}", history_size);
+        if let Ok(msgs) = crate::message_cache::fetch_messages(&ctx.http, channel, &query) {
             on_main(move |weechat| {
                 let ctx = match crate::discord::get_ctx() {
                     Some(ctx) => ctx,
@@ -449,15 +473,27 @@ pub fn load_history(buffer: &weechat::Buffer, completion_sender: crossbeam_chann
                 };
                 let buf = sealed_buffer.unseal(&weechat);
 
+                let oldest = msgs.last().map(|msg| msg.id).unwrap_or(MessageId(0));
+                buf.set_localvar("oldest_msgid", &oldest.0.to_string());
+
                 use crate::printing;
+                // Live messages may have been printed while the history was loading, or before
+                // the buffer was first switched to, so put the history above them
+                if printing::has_message_lines(&buf) {
+                    printing::insert_messages(weechat, &buf, &msgs, false);
+                    completion_sender.send(()).unwrap();
+                    return;
+                }
+
+                // Pages are returned newest first
+                let msgs: Vec<_> = msgs.into_iter().rev().collect();
                 if let Some(read_state) = ctx.cache.read().read_state.get(&channel) {
                     let unread_in_page = msgs.iter().any(|m| m.id == read_state.last_message_id);
 
                     if unread_in_page {
                         let mut backlog = true;
-                        for msg in msgs.into_iter().rev() {
+                        for msg in msgs {
                             printing::print_msg(&weechat, &buf, &msg, false);
-                            printing::inject_msg_id(msg.id, &buf);
                             if backlog {
                                 buf.mark_read();
                                 buf.clear_hotlist();
@@ -469,15 +505,13 @@ pub fn load_history(buffer: &weechat::Buffer, completion_sender: crossbeam_chann
                     } else {
                         buf.mark_read();
                         buf.clear_hotlist();
-                        for msg in msgs.into_iter().rev() {
+                        for msg in msgs {
                             printing::print_msg(&weechat, &buf, &msg, false);
-                            printing::inject_msg_id(msg.id, &buf);
                         }
                     }
                 } else {
-                    for msg in msgs.into_iter().rev() {
+                    for msg in msgs {
                         printing::print_msg(&weechat, &buf, &msg, false);
-                        printing::inject_msg_id(msg.id, &buf);
                     }
                 }
                 completion_sender.send(()).unwrap();
@@ -486,6 +520,100 @@ pub fn load_history(buffer: &weechat::Buffer, completion_sender: crossbeam_chann
     });
 }
 
+/// How far back `load_older_history` should go
+#[derive(Clone, Copy)]
+pub enum HistoryRequest {
+    Count(u64),
+    Since(DateTime<FixedOffset>),
+}
+
+/// Page backwards from the oldest loaded message, then insert the older messages at the top of the
+/// buffer
+pub fn load_older_history(buffer: &Buffer, request: HistoryRequest) {
+    let channel = match buffer.get_localvar("channelid") {
+        Some(ch) => ch,
+        None => return,
+    };
+    let channel = match channel.parse::<u64>() {
+        Ok(v) => ChannelId(v),
+        Err(_) => return,
+    };
+    let oldest = match buffer
+        .get_localvar("oldest_msgid")
+        .and_then(|id| id.parse::<u64>().ok())
+    {
+        Some(id) => MessageId(id),
+        None => {
+            crate::plugin_print("History has not been loaded for this buffer yet");
+            return;
+        }
+    };
+
+    let sealed_buffer = buffer.seal();
+
+    std::thread::spawn(move || {
+        let ctx = match crate::discord::get_ctx() {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+
+        let mut before = oldest;
+        let mut fetched = Vec::new();
+        loop {
+            let page_size = match request {
+                HistoryRequest::Count(n) => (n - fetched.len() as u64).min(100),
+                HistoryRequest::Since(_) => 100,
+            };
+            if page_size == 0 {
+                break;
+            }
+
+            let query = format!("?limit={}&before={}", page_size, before);
+            let page = match crate::message_cache::fetch_messages(&ctx.http, channel, &query) {
+                Ok(page) => page,
+                Err(e) => {
+                    crate::plugin_print(&format!("Unable to load history: {}", e));
+                    break;
+                }
+            };
+            let page_len = page.len() as u64;
+
+            // Pages are returned newest first
+            let mut reached_date = false;
+            for msg in page {
+                before = msg.id;
+                if let HistoryRequest::Since(date) = request {
+                    if msg.timestamp < date {
+                        reached_date = true;
+                        break;
+                    }
+                }
+                fetched.push(msg);
+            }
+
+            if reached_date || page_len < page_size {
+                break;
+            }
+        }
+
+        let new_oldest = match fetched.last() {
+            Some(msg) => msg.id,
+            None => {
+                crate::plugin_print("No older messages");
+                return;
+            }
+        };
+        let count = fetched.len();
+
+        on_main(move |weechat| {
+            let buf = sealed_buffer.unseal(&weechat);
+            buf.set_localvar("oldest_msgid", &new_oldest.0.to_string());
+            crate::printing::insert_messages(weechat, &buf, &fetched, false);
+            crate::plugin_print(&format!("Loaded {} older messages", count));
+        });
+    });
+}
+
 pub fn load_dm_nicks(buffer: &Buffer, channel: &PrivateChannel) {
     let weechat = buffer.get_weechat();
     let use_presence = crate::upgrade_plugin(&weechat).config.use_presence.value();
@@ -558,50 +686,199 @@ pub fn load_nicks(buffer: &Buffer) {
     buffer.set_localvar("loaded_nicks", "true");
     buffer.enable_nicklist();
 
-    let sealed_buffer = buffer.seal();
+    let weechat = buffer.get_weechat();
+    // Members are added to a guild's nicklists once, and a buffer opened later starts with the
+    // members shown so far
+    {
+        let mut guilds = GUILD_MEMBERS.lock();
+        if let Some(shared) = guilds.get(&guild_id) {
+            add_members_to_nicklist(&weechat, buffer, channel_id, guild_id, &shared.members);
+            if let Some(group) = &shared.more_group {
+                buffer.add_group(group, "grey", true, None);
+            }
+            return;
+        }
+        guilds.insert(guild_id, GuildMembers::default());
+    }
 
+    // Members arrive from the gateway in chunks, which serenity collects in the guild's cache.
+    // Those already cached are added a batch at a time in the background, and the rest are added
+    // as their chunks arrive
     std::thread::spawn(move || {
         let ctx = match crate::discord::get_ctx() {
             Some(ctx) => ctx,
             _ => return,
         };
+        let (members, member_count) = match guild_id.to_guild_cached(ctx) {
+            Some(guild) => {
+                let guild = guild.read();
+                let members: Vec<_> = guild.members.values().cloned().collect();
+                (members, guild.member_count)
+            }
+            None => return,
+        };
 
-        let guild = guild_id.to_guild_cached(ctx).expect("No guild cache item");
+        // The chunks requested when connecting may have been cut short or not have arrived yet,
+        // in which case the missing members are added as they do
+        if (members.len() as u64) < member_count {
+            ctx.shard.chunk_guilds(vec![guild_id], None, None);
+        }
 
-        // Typeck not smart enough
-        let none_user: Option<UserId> = None;
-        // TODO: What to do with more than 1000 members?
-        let members = guild.read().members(ctx, Some(1000), none_user).unwrap();
+        for batch in members.chunks(MEMBER_BATCH_SIZE) {
+            add_guild_members(guild_id, batch.to_vec());
+        }
+        on_main(move |weechat| update_more_members_group(weechat, guild_id));
+    });
+}
 
-        drop(guild);
+/// Add members of a guild to the nicklists of its channels, up to
+/// weecord.main.max_nicklist_members
+pub fn add_guild_members(guild_id: GuildId, members: Vec<Member>) {
+    // Shared members are only changed on the main thread, so that no buffer misses a member or
+    // is sent one twice
+    on_main(move |weechat| {
+        let max_members = crate::upgrade_plugin(weechat)
+            .config
+            .max_nicklist_members
+            .value() as usize;
+        {
+            let mut guilds = GUILD_MEMBERS.lock();
+            let shared = match guilds.get_mut(&guild_id) {
+                Some(shared) => shared,
+                None => return,
+            };
+            let mut added = Vec::new();
+            for member in members {
+                if max_members != 0 && shared.members.len() >= max_members {
+                    break;
+                }
+                if shared.ids.insert(member.user_id()) {
+                    shared.members.push(member.clone());
+                    added.push(member);
+                }
+            }
+            for (buffer, channel_id) in nicklist_buffers(weechat, guild_id) {
+                add_members_to_nicklist(weechat, &buffer, channel_id, guild_id, &added);
+            }
+        }
+        update_more_members_group(weechat, guild_id);
+    });
+}
 
-        let () = on_main_blocking(move |weechat| {
-            let ctx = match crate::discord::get_ctx() {
-                Some(ctx) => ctx,
-                _ => return,
+/// Remove a member who left a guild from the nicklists of its channels
+pub fn remove_guild_member(guild_id: GuildId, user_id: UserId) {
+    on_main(move |weechat| {
+        {
+            let mut guilds = GUILD_MEMBERS.lock();
+            let shared = match guilds.get_mut(&guild_id) {
+                Some(shared) => shared,
+                None => return,
             };
+            if shared.ids.remove(&user_id) {
+                if let Some(index) = shared.members.iter().position(|m| m.user_id() == user_id) {
+                    let member = shared.members.remove(index);
+                    for (buffer, _) in nicklist_buffers(weechat, guild_id) {
+                        if let Some(nick) = buffer.search_nick(&member.display_name(), None) {
+                            nick.remove();
+                        }
+                    }
+                }
+            }
+        }
+        update_more_members_group(weechat, guild_id);
+    });
+}
 
-            let use_presence = weechat.config.use_presence.value();
+/// The open buffers of a guild's channels that show its members, and their channels
+fn nicklist_buffers(weechat: &Weechat, guild_id: GuildId) -> Vec<(Buffer, ChannelId)> {
+    let guild = match crate::discord::get_ctx().and_then(|ctx| guild_id.to_guild_cached(ctx)) {
+        Some(guild) => guild,
+        None => return Vec::new(),
+    };
+    let channels: Vec<_> = guild.read().channels.keys().copied().collect();
+    channels
+        .into_iter()
+        .filter_map(|channel_id| {
+            let buffer_name = utils::buffer_id_for_channel(Some(guild_id), channel_id);
+            let buffer = weechat.buffer_search("weecord", &buffer_name)?;
+            buffer.get_localvar("loaded_nicks")?;
+            Some((buffer, channel_id))
+        })
+        .collect()
+}
 
-            let buffer = sealed_buffer.unseal(&weechat);
-            let guild = guild_id.to_guild_cached(ctx).expect("No guild cache item");
+fn add_members_to_nicklist(
+    weechat: &Weechat,
+    buffer: &Buffer,
+    channel_id: ChannelId,
+    guild_id: GuildId,
+    members: &[Member],
+) {
+    let ctx = match crate::discord::get_ctx() {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+    let guild = match guild_id.to_guild_cached(ctx) {
+        Some(guild) => guild,
+        None => return,
+    };
 
-            let has_crown = guild_has_crown(&guild.read());
+    let use_presence = crate::upgrade_plugin(weechat).config.use_presence.value();
+    let has_crown = guild_has_crown(&guild.read());
+
+    for member in members {
+        add_member_to_nicklist(
+            weechat,
+            &ctx,
+            buffer,
+            channel_id,
+            &guild,
+            member,
+            use_presence,
+            has_crown,
+        );
+    }
+}
 
-            for member in members {
-                add_member_to_nicklist(
-                    weechat,
-                    &ctx,
-                    &buffer,
-                    channel_id,
-                    &guild,
-                    &member,
-                    use_presence,
-                    has_crown,
-                );
-            }
-        });
-    });
+/// Summarize the members of a guild that aren't in its nicklists as "+N more"
+fn update_more_members_group(weechat: &Weechat, guild_id: GuildId) {
+    let member_count = match crate::discord::get_ctx().and_then(|ctx| guild_id.to_guild_cached(ctx))
+    {
+        Some(guild) => guild.read().member_count,
+        None => return,
+    };
+    let mut guilds = GUILD_MEMBERS.lock();
+    let shared = match guilds.get_mut(&guild_id) {
+        Some(shared) => shared,
+        None => return,
+    };
+    let shown = shared.members.len() as u64;
+    let group = if member_count > shown {
+        Some(format!(
+            "{}+{} more",
+            MORE_MEMBERS_GROUP_PREFIX,
+            member_count - shown
+        ))
+    } else {
+        None
+    };
+    if group == shared.more_group {
+        return;
+    }
+
+    for (buffer, _) in nicklist_buffers(weechat, guild_id) {
+        if let Some(old) = shared
+            .more_group
+            .as_ref()
+            .and_then(|name| buffer.search_nicklist_group(name))
+        {
+            old.remove();
+        }
+        if let Some(name) = &group {
+            buffer.add_group(name, "grey", true, None);
+        }
+    }
+    shared.more_group = group;
 }
 
 fn add_member_to_nicklist(
@@ -637,14 +914,15 @@ fn add_member_to_nicklist(
     } else if !online && use_presence {
         role_name = OFFLINE_GROUP_NAME.to_owned();
         role_color = "grey".to_string();
-    } else if let Some((highest_hoisted, highest)) = utils::find_highest_roles(&ctx.cache, &member)
-    {
+    } else if let Some((highest_hoisted, _)) = utils::find_highest_roles(&ctx.cache, &member) {
         role_name = format!(
             "{}|{}",
             99999 - highest_hoisted.position,
             highest_hoisted.name
         );
-        role_color = crate::utils::rgb_to_ansi(highest.colour).to_string();
+        role_color = utils::find_highest_colored_role(&ctx.cache, &member)
+            .map(|role| utils::color_name(weechat, role.colour))
+            .unwrap_or_else(|| "default".to_string());
     } else {
         // Can't find a role, add user to generic bucket
         if use_presence {
@@ -715,54 +993,51 @@ pub fn update_nick() {
     }
 }
 
-pub fn update_member_nick(old: &Option<Member>, new: &Member) {
-    let old_nick = match old.as_ref().map(|old| old.display_name()) {
-        Some(old) => old,
-        None => {
-            // TODO: Rebuild entire nicklist?
-            return;
-        }
-    };
-    let new_nick = new.display_name();
-    let new = new.clone();
-    let guild_id = new.guild_id;
-
-    if old_nick != new_nick {
-        let old_nick = old_nick.to_owned().to_string();
+/// Replace a member in the nicklists of their guild's channels, in case their nick or roles
+/// changed
+pub fn update_guild_member(member: &Member) {
+    let member = member.clone();
+    on_main(move |weechat| {
         let ctx = match crate::discord::get_ctx() {
             Some(ctx) => ctx,
             _ => return,
         };
+        let guild_id = member.guild_id;
+        let user_id = member.user_id();
+        let mut guilds = GUILD_MEMBERS.lock();
+        let shared = match guilds.get_mut(&guild_id) {
+            Some(shared) => shared,
+            None => return,
+        };
+        let old = match shared.members.iter_mut().find(|m| m.user_id() == user_id) {
+            Some(old) => old,
+            None => return,
+        };
+        let old_nick = old.display_name().into_owned();
+        *old = member.clone();
 
-        let channels = guild_id.channels(ctx).expect("Unable to fetch channels");
-
-        on_main(move |weechat| {
-            let ctx = match crate::discord::get_ctx() {
-                Some(ctx) => ctx,
-                _ => return,
-            };
-            for channel_id in channels.keys() {
-                let string_channel = utils::buffer_id_for_channel(Some(guild_id), *channel_id);
-                if let Some(buffer) = weechat.buffer_search("weecord", &string_channel) {
-                    if let Some(nick) = buffer.search_nick(&old_nick, None) {
-                        nick.remove();
-                        if let Some(guild) = guild_id.to_guild_cached(&ctx) {
-                            add_member_to_nicklist(
-                                weechat,
-                                &ctx,
-                                &buffer,
-                                *channel_id,
-                                &guild,
-                                &new,
-                                false,
-                                guild_has_crown(&guild.read()),
-                            );
-                        }
-                    }
-                }
+        let guild = match guild_id.to_guild_cached(&ctx) {
+            Some(guild) => guild,
+            None => return,
+        };
+        let use_presence = crate::upgrade_plugin(weechat).config.use_presence.value();
+        let has_crown = guild_has_crown(&guild.read());
+        for (buffer, channel_id) in nicklist_buffers(weechat, guild_id) {
+            if let Some(nick) = buffer.search_nick(&old_nick, None) {
+                nick.remove();
             }
-        })
-    }
+            add_member_to_nicklist(
+                weechat,
+                &ctx,
+                &buffer,
+                channel_id,
+                &guild,
+                &member,
+                use_presence,
+                has_crown,
+            );
+        }
+    })
 }
 
 fn guild_has_crown(guild: &Guild) -> bool {
@@ -1,16 +1,21 @@
 use crate::utils::{ChannelExt, GuildOrChannel};
-use crate::{buffers, discord, on_main_blocking, plugin_print, utils};
+use crate::{buffers, discord, on_main, on_main_blocking, plugin_print, utils};
+use chrono::TimeZone;
 use lazy_static::lazy_static;
 use parking_lot::Mutex;
+use serenity::model::channel::Message;
 use serenity::model::gateway::Activity;
-use serenity::model::id::ChannelId;
+use serenity::model::id::{ChannelId, GuildId, MessageId, UserId};
 use serenity::model::user::OnlineStatus;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use weechat::{Buffer, CommandHook, ConfigOption, ReturnCode, Weechat};
 
 lazy_static! {
     // Tracks the last set status for use in setting the current game presence
     pub static ref LAST_STATUS: Arc<Mutex<OnlineStatus>> = Arc::new(Mutex::new(OnlineStatus::Online));
+    /// The message the delete key was last pressed on and when, waiting for a second press
+    static ref PENDING_DELETE: Mutex<Option<(MessageId, Instant)>> = Mutex::new(None);
 }
 
 pub fn init(weechat: &Weechat) -> CommandHook<()> {
@@ -79,6 +84,18 @@ fn run_command(buffer: &Buffer, cmd: &str) {
         "status" => status(args),
         "game" => game(args),
         "upload" => upload(args, buffer),
+        "reply" => reply(args, buffer),
+        "edit" => edit(args, buffer),
+        "quote" => quote(args, buffer),
+        "cursor" => cursor(weechat, args),
+        "history" => history(weechat, args, buffer),
+        "resend" => resend(buffer),
+        "multiline" => multiline(args, buffer),
+        "download" => download(weechat, args, buffer),
+        "reveal" => reveal(args, buffer),
+        "edits" => edits(args, buffer),
+        "react" => react(args, buffer, false),
+        "unreact" => react(args, buffer, true),
         "me" | "tableflip" | "unflip" | "shrug" | "spoiler" => {
             discord_fmt(args.base, args.rest, buffer)
         }
@@ -471,16 +488,7 @@ fn upload(args: Args, buffer: &Buffer) {
     if args.args.is_empty() {
         plugin_print("upload requires an argument");
     } else {
-        let mut file = args.rest.to_owned();
-        // TODO: Find a better way to expand paths
-        if file.starts_with("~/") {
-            let rest: String = file.chars().skip(2).collect();
-            let dir = match dirs::home_dir() {
-                Some(dir) => dir.to_string_lossy().into_owned(),
-                None => ".".to_owned(),
-            };
-            file = format!("{}/{}", dir, rest);
-        }
+        let file = utils::expand_home(args.rest);
         let full = match std::fs::canonicalize(file) {
             Ok(f) => f.to_string_lossy().into_owned(),
             Err(e) => {
@@ -488,7 +496,6 @@ fn upload(args: Args, buffer: &Buffer) {
                 return;
             }
         };
-        let full = full.as_str();
         // TODO: Check perms and file size
         let channel = match buffer.get_localvar("channelid") {
             Some(channel) => channel,
@@ -498,21 +505,720 @@ fn upload(args: Args, buffer: &Buffer) {
             Ok(v) => ChannelId(v),
             Err(_) => return,
         };
+        discord::send_queue::queue_upload(
+            buffer,
+            channel,
+            discord::send_queue::Attachment::File(full),
+        );
+    }
+}
+
+fn history(weechat: &Weechat, args: Args, buffer: &Buffer) {
+    let request = match args.args.get(0).copied() {
+        None | Some("") => buffers::HistoryRequest::Count(
+            crate::upgrade_plugin(weechat).config.history_size.value() as u64,
+        ),
+        Some("all-since") => {
+            let date = match args.args.get(1) {
+                Some(date) => date,
+                None => {
+                    plugin_print("history all-since requires a date (YYYY-MM-DD)");
+                    return;
+                }
+            };
+            let date = match chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+                Ok(date) => date,
+                Err(e) => {
+                    plugin_print(&format!("Invalid date \"{}\": {}", date, e));
+                    return;
+                }
+            };
+            let date = chrono::Local
+                .from_local_date(&date)
+                .earliest()
+                .map(|d| d.and_hms(0, 0, 0));
+            match date {
+                Some(date) => buffers::HistoryRequest::Since(date.with_timezone(date.offset())),
+                None => {
+                    plugin_print("Invalid date");
+                    return;
+                }
+            }
+        }
+        Some(n) => match n.parse() {
+            Ok(n) => buffers::HistoryRequest::Count(n),
+            Err(_) => {
+                plugin_print(&format!("Invalid message count \"{}\"", n));
+                return;
+            }
+        },
+    };
+
+    buffers::load_older_history(buffer, request);
+}
+
+fn multiline(args: Args, buffer: &Buffer) {
+    match args.args.get(0).copied() {
+        Some("start") => {
+            if crate::hook::start_multiline(buffer) {
+                plugin_print("Collecting input, run /discord multiline end to send it");
+            }
+        }
+        Some("end") => crate::hook::end_multiline(buffer),
+        _ => plugin_print("multiline requires either start or end"),
+    }
+}
+
+fn resend(buffer: &Buffer) {
+    let channel = match buffer.get_localvar("channelid") {
+        Some(channel) => channel,
+        None => return,
+    };
+    let channel = match channel.parse::<u64>() {
+        Ok(v) => ChannelId(v),
+        Err(_) => return,
+    };
+
+    match discord::send_queue::resend_failed(buffer, channel) {
+        0 => plugin_print("No failed messages to resend"),
+        n => plugin_print(&format!("Resending {} messages", n)),
+    }
+}
+
+fn reply(args: Args, buffer: &Buffer) {
+    if args.args.len() < 2 {
+        plugin_print("reply requires a message number and a message");
+        return;
+    }
+    let n = match parsing::parse_message_arg(args.args[0]) {
+        Some(n) => n,
+        None => {
+            plugin_print(&format!("Invalid message number \"{}\"", args.args[0]));
+            return;
+        }
+    };
+    let text = args.rest[args.args[0].len()..].trim();
+
+    let channel = match buffer.get_localvar("channelid") {
+        Some(channel) => channel,
+        None => return,
+    };
+    let channel = match channel.parse::<u64>() {
+        Ok(v) => ChannelId(v),
+        Err(_) => return,
+    };
+    let guild = buffer
+        .get_localvar("guildid")
+        .and_then(|id| id.parse().ok())
+        .map(GuildId);
+    let ctx = match crate::discord::get_ctx() {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+
+    let text = utils::create_mentions(&ctx.cache, guild, text);
+    let buffer_name = utils::buffer_id_for_channel(guild, channel);
+
+    // Finding the nth message can take several requests
+    std::thread::spawn(move || {
         let ctx = match crate::discord::get_ctx() {
             Some(ctx) => ctx,
             _ => return,
         };
-        match channel.send_files(ctx, vec![full], |m| m) {
-            Ok(_) => plugin_print("File uploaded successfully"),
+        let target = utils::get_message(ctx, channel, n).map_err(|e| e.to_string());
+        on_main(move |weechat| match target {
+            Ok(target) => {
+                if let Some(buffer) = weechat.buffer_search("weecord", &buffer_name) {
+                    discord::send_queue::queue_reply(&buffer, channel, text, target.id);
+                }
+            }
+            Err(e) => plugin_print(&format!("Unable to find message {}: {}", n, e)),
+        });
+    });
+}
+
+fn quote(args: Args, buffer: &Buffer) {
+    let n = match args.args.get(0).map(|n| parsing::parse_message_arg(n)) {
+        Some(Some(n)) => n,
+        Some(None) => {
+            plugin_print(&format!("Invalid message number \"{}\"", args.args[0]));
+            return;
+        }
+        None => {
+            plugin_print("quote requires a message number");
+            return;
+        }
+    };
+    let text = args.rest[args.args[0].len()..].trim();
+
+    let channel = match buffer.get_localvar("channelid") {
+        Some(channel) => channel,
+        None => return,
+    };
+    let channel = match channel.parse::<u64>() {
+        Ok(v) => ChannelId(v),
+        Err(_) => return,
+    };
+    let guild = buffer
+        .get_localvar("guildid")
+        .and_then(|id| id.parse().ok())
+        .map(GuildId);
+    let text = text.to_owned();
+    let buffer_name = utils::buffer_id_for_channel(guild, channel);
+
+    // Finding the nth message can take several requests
+    std::thread::spawn(move || {
+        let ctx = match crate::discord::get_ctx() {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        let quoted = match utils::get_message(ctx, channel, n) {
+            Ok(msg) => msg,
+            Err(e) => {
+                plugin_print(&format!("Unable to find message {}: {}", n, e));
+                return;
+            }
+        };
+        // The quoted content is already in Discord's syntax, only our own text needs converting
+        let mut message = quoted
+            .content
+            .lines()
+            .map(|line| format!("> {}", line))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if !text.is_empty() {
+            message.push('\n');
+            message.push_str(&utils::create_mentions(&ctx.cache, guild, &text));
+        }
+        on_main(move |weechat| {
+            if let Some(buffer) = weechat.buffer_search("weecord", &buffer_name) {
+                crate::hook::send_discord_text(&buffer, channel, message);
+            }
+        });
+    });
+}
+
+/// Act on the message under the cursor or mouse, used by the cursor and mouse bindings
+///
+/// The message id and author come from the focus info added by `hook::handle_chat_focus`
+fn cursor(weechat: &Weechat, args: Args) {
+    let (action, buffer_name, message_id, author) = match args.args.as_slice() {
+        [action, buffer_name, message_id, author] => (*action, *buffer_name, *message_id, *author),
+        _ => {
+            plugin_print("cursor requires an action, a buffer name, a message id and an author");
+            return;
+        }
+    };
+    let buffer = match weechat.buffer_search("weecord", buffer_name) {
+        Some(buffer) => buffer,
+        None => return,
+    };
+    // Bindings pass the focus variables through unexpanded when the line isn't a message
+    let message_id = match message_id.parse() {
+        Ok(id) => MessageId(id),
+        Err(_) => {
+            plugin_print("The selected line is not a Discord message");
+            return;
+        }
+    };
+    let author = author.parse().ok().map(UserId);
+    let channel = match buffer
+        .get_localvar("channelid")
+        .and_then(|id| id.parse().ok())
+        .map(ChannelId)
+    {
+        Some(channel) => channel,
+        None => return,
+    };
+    let guild = buffer
+        .get_localvar("guildid")
+        .and_then(|id| id.parse().ok())
+        .map(GuildId);
+    let ctx = match crate::discord::get_ctx() {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+
+    if action != "delete" {
+        PENDING_DELETE.lock().take();
+    }
+    match action {
+        "reply" => buffer.set_input(&format!("/discord reply #{} ", message_id)),
+        "quote" => buffer.set_input(&format!("/discord quote #{} ", message_id)),
+        "react" => buffer.set_input(&format!("#{}+:", message_id)),
+        "link" => {
+            let guild = match guild {
+                Some(guild) => guild.to_string(),
+                None => "@me".to_owned(),
+            };
+            let link = format!(
+                "https://discord.com/channels/{}/{}/{}",
+                guild, channel, message_id
+            );
+            let buffer_name = buffer_name.to_owned();
+            // Clipboard tools can take a moment to start
+            std::thread::spawn(move || {
+                if utils::copy_to_clipboard(&link) {
+                    plugin_print("Copied the link to the message");
+                    return;
+                }
+                on_main(move |weechat| {
+                    if let Some(buffer) = weechat.buffer_search("weecord", &buffer_name) {
+                        buffer.print(&format!(
+                            "[discord] No clipboard tool found, the link is {}",
+                            link
+                        ));
+                    }
+                });
+            });
+        }
+        "edit" => {
+            let buffer_name = buffer_name.to_owned();
+            std::thread::spawn(move || {
+                let ctx = match crate::discord::get_ctx() {
+                    Some(ctx) => ctx,
+                    _ => return,
+                };
+                let target = parsing::MessageTarget::Id(message_id.0);
+                let msg = utils::get_users_message(ctx, channel, target).map_err(|e| e.to_string());
+                on_main(move |weechat| match msg {
+                    Ok(msg) => {
+                        if let Some(buffer) = weechat.buffer_search("weecord", &buffer_name) {
+                            crate::hook::start_edit(&buffer, &msg);
+                        }
+                    }
+                    Err(e) => plugin_print(&format!("Unable to edit message: {}", e)),
+                });
+            });
+        }
+        "delete" => {
+            if author != Some(ctx.cache.read().user.id) {
+                PENDING_DELETE.lock().take();
+                buffer.print("[discord] You can only delete your own messages");
+                return;
+            }
+
+            let confirmed = {
+                let mut pending = PENDING_DELETE.lock();
+                match pending.take() {
+                    Some((id, at)) if id == message_id && at.elapsed() < DELETE_CONFIRM_TIME => {
+                        true
+                    }
+                    _ => {
+                        *pending = Some((message_id, Instant::now()));
+                        false
+                    }
+                }
+            };
+            if !confirmed {
+                buffer.print("[discord] Press d again to delete this message");
+                return;
+            }
+
+            buffer.run_command("/cursor stop");
+            std::thread::spawn(move || {
+                let ctx = match crate::discord::get_ctx() {
+                    Some(ctx) => ctx,
+                    _ => return,
+                };
+                if let Err(e) = channel.delete_message(&ctx.http, message_id) {
+                    plugin_print(&format!("An error occurred deleting a message: {}", e));
+                }
+            });
+        }
+        _ => plugin_print(&format!("Unknown cursor action \"{}\"", action)),
+    }
+}
+
+/// How long after the first press of the delete key pressing it again deletes the message
+const DELETE_CONFIRM_TIME: Duration = Duration::from_secs(5);
+
+/// Key and mouse bindings over weecord buffers: their context, key, and the action they run
+const MESSAGE_BINDINGS: &[(&str, &str, &str)] = &[
+    ("cursor", "r", "reply"),
+    ("cursor", "e", "edit"),
+    ("cursor", "d", "delete"),
+    ("cursor", "+", "react"),
+    ("cursor", "l", "link"),
+    ("cursor", "q", "quote"),
+    ("mouse", "ctrl-button1", "reply"),
+    ("mouse", "ctrl-button2", "react"),
+];
+
+/// The command a binding from `MESSAGE_BINDINGS` runs
+fn binding_command(context: &str, action: &str) -> String {
+    let command = format!(
+        "/discord cursor {} ${{_buffer_name}} ${{_discord_msgid}} ${{_discord_author}}",
+        action
+    );
+    // Deleting asks for the key to be pressed again, so it stays in cursor mode until then
+    if context == "cursor" && action != "delete" {
+        command + ";/cursor stop"
+    } else {
+        command
+    }
+}
+
+/// Whether a key is bound to one of weecord's commands, including those of older versions
+fn is_weecord_binding(command: &str) -> bool {
+    command.starts_with("/discord cursor ")
+}
+
+/// Bind or unbind the keys and mouse actions that act on messages, following
+/// weecord.main.cursor_keys
+///
+/// Keys the user has bound to something else are left alone
+pub(crate) fn update_cursor_keys(weechat: &Weechat, enabled: bool) {
+    for (context, key, action) in MESSAGE_BINDINGS {
+        let key = format!("@chat(weecord.*):{}", key);
+        let bound = utils::key_binding(weechat, context, &key);
+        if bound
+            .as_ref()
+            .map_or(false, |bound| !is_weecord_binding(bound))
+        {
+            continue;
+        }
+        if enabled {
+            let command = binding_command(context, action);
+            if bound.as_ref() != Some(&command) {
+                utils::run_core_command(
+                    weechat,
+                    &format!("/key bindctxt {} {} {}", context, key, command),
+                );
+            }
+        } else if bound.is_some() {
+            utils::run_core_command(weechat, &format!("/key unbindctxt {} {}", context, key));
+        }
+    }
+}
+
+fn download(weechat: &Weechat, args: Args, buffer: &Buffer) {
+    let n = match args.args.get(0).map(|n| parsing::parse_message_arg(n)) {
+        Some(Some(n)) => n,
+        Some(None) => {
+            plugin_print(&format!("Invalid message number \"{}\"", args.args[0]));
+            return;
+        }
+        None => {
+            plugin_print("download requires a message number");
+            return;
+        }
+    };
+    let dir = match args.args.get(1) {
+        Some(dir) => utils::expand_home(dir),
+        None => utils::expand_home(
+            &crate::upgrade_plugin(weechat)
+                .config
+                .download_dir
+                .value()
+                .into_owned(),
+        ),
+    };
+
+    let channel = match buffer.get_localvar("channelid") {
+        Some(channel) => channel,
+        None => return,
+    };
+    let channel = match channel.parse::<u64>() {
+        Ok(v) => ChannelId(v),
+        Err(_) => return,
+    };
+    let ctx = match crate::discord::get_ctx() {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+
+    std::thread::spawn(move || {
+        let msg = match utils::get_message(ctx, channel, n) {
+            Ok(msg) => msg,
             Err(e) => {
-                if let serenity::Error::Model(serenity::model::ModelError::MessageTooLong(_)) = e {
-                    plugin_print("File too large to upload");
+                plugin_print(&format!("Unable to find message {}: {}", n, e));
+                return;
+            }
+        };
+        if msg.attachments.is_empty() {
+            plugin_print(&format!("Message {} has no attachments", n));
+            return;
+        }
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            plugin_print(&format!("Unable to create directory {}: {}", dir, e));
+            return;
+        }
+
+        for attachment in &msg.attachments {
+            plugin_print(&format!(
+                "Downloading {} ({})...",
+                attachment.filename,
+                utils::human_size(attachment.size)
+            ));
+            let path = match utils::download_path(std::path::Path::new(&dir), &attachment.filename)
+            {
+                Some(path) => path,
+                None => {
+                    plugin_print(&format!(
+                        "Unable to download {}: invalid filename",
+                        attachment.filename
+                    ));
+                    continue;
                 }
+            };
+            let result = attachment
+                .download()
+                .map_err(|e| e.to_string())
+                .and_then(|bytes| std::fs::write(&path, bytes).map_err(|e| e.to_string()));
+            match result {
+                Ok(()) => plugin_print(&format!(
+                    "Saved {} to {}",
+                    attachment.filename,
+                    path.display()
+                )),
+                Err(e) => plugin_print(&format!(
+                    "Unable to download {}: {}",
+                    attachment.filename, e
+                )),
+            }
+        }
+    });
+}
+
+fn edit(args: Args, buffer: &Buffer) {
+    if args.args.get(0) == Some(&"cancel") {
+        if crate::hook::cancel_edit(buffer).is_some() {
+            buffer.set_input("");
+        }
+        return;
+    }
+    let n = match args.args.get(0).copied() {
+        None | Some("") => parsing::MessageTarget::Line(1),
+        Some(n) => match parsing::parse_message_arg(n) {
+            Some(n) => n,
+            None => {
+                plugin_print(&format!("Invalid message number \"{}\"", n));
+                return;
             }
+        },
+    };
+
+    let channel = match buffer.get_localvar("channelid") {
+        Some(channel) => channel,
+        None => return,
+    };
+    let channel = match channel.parse::<u64>() {
+        Ok(v) => ChannelId(v),
+        Err(_) => return,
+    };
+    let guild = buffer
+        .get_localvar("guildid")
+        .and_then(|id| id.parse().ok())
+        .map(GuildId);
+    let buffer_name = utils::buffer_id_for_channel(guild, channel);
+
+    // Finding the nth message can take several requests
+    std::thread::spawn(move || {
+        let ctx = match crate::discord::get_ctx() {
+            Some(ctx) => ctx,
+            _ => return,
         };
+        let msg = utils::get_users_message(ctx, channel, n).map_err(|e| e.to_string());
+        on_main(move |weechat| match msg {
+            Ok(msg) => {
+                if let Some(buffer) = weechat.buffer_search("weecord", &buffer_name) {
+                    crate::hook::start_edit(&buffer, &msg);
+                }
+            }
+            Err(e) => plugin_print(&format!("Unable to find message {}: {}", n, e)),
+        });
+    });
+}
+
+fn reveal(args: Args, buffer: &Buffer) {
+    let n = match args.args.get(0).map(|n| parsing::parse_message_arg(n)) {
+        Some(Some(n)) => n,
+        Some(None) => {
+            plugin_print(&format!("Invalid message number \"{}\"", args.args[0]));
+            return;
+        }
+        None => {
+            plugin_print("reveal requires a message number");
+            return;
+        }
+    };
+
+    let channel = match buffer.get_localvar("channelid") {
+        Some(channel) => channel,
+        None => return,
+    };
+    let channel = match channel.parse::<u64>() {
+        Ok(v) => ChannelId(v),
+        Err(_) => return,
+    };
+    let guild = buffer
+        .get_localvar("guildid")
+        .and_then(|id| id.parse().ok())
+        .map(GuildId);
+
+    // Finding the nth message can take several requests
+    std::thread::spawn(move || {
+        let ctx = match crate::discord::get_ctx() {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        let msg = match utils::get_message(ctx, channel, n) {
+            Ok(msg) => msg,
+            Err(e) => {
+                plugin_print(&format!("Unable to find message {}: {}", n, e));
+                return;
+            }
+        };
+
+        on_main(move |weecord| {
+            let ctx = match crate::discord::get_ctx() {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            crate::printing::reveal_spoilers(msg.id);
+            let (_, content) = crate::printing::render_msg(&ctx.cache, weecord, &msg, guild);
+            discord::event_handler::modify_buffer_lines(
+                weecord,
+                msg.id,
+                utils::buffer_id_for_channel(guild, channel),
+                content,
+            );
+        });
+    });
+}
+
+fn edits(args: Args, buffer: &Buffer) {
+    let n = match args.args.get(0).map(|n| parsing::parse_message_arg(n)) {
+        Some(Some(n)) => n,
+        Some(None) => {
+            plugin_print(&format!("Invalid message number \"{}\"", args.args[0]));
+            return;
+        }
+        None => {
+            plugin_print("edits requires a message number");
+            return;
+        }
+    };
+
+    let channel = match buffer.get_localvar("channelid") {
+        Some(channel) => channel,
+        None => return,
+    };
+    let channel = match channel.parse::<u64>() {
+        Ok(v) => ChannelId(v),
+        Err(_) => return,
+    };
+    let guild = buffer
+        .get_localvar("guildid")
+        .and_then(|id| id.parse().ok())
+        .map(GuildId);
+    let buffer_name = utils::buffer_id_for_channel(guild, channel);
+
+    // Finding the nth message can take several requests
+    std::thread::spawn(move || {
+        let ctx = match crate::discord::get_ctx() {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        let msg = match utils::get_message(ctx, channel, n) {
+            Ok(msg) => msg,
+            Err(e) => {
+                plugin_print(&format!("Unable to find message {}: {}", n, e));
+                return;
+            }
+        };
+        on_main(move |weechat| {
+            if let Some(buffer) = weechat.buffer_search("weecord", &buffer_name) {
+                print_edits(weechat, &buffer, &msg, n, guild);
+            }
+        });
+    });
+}
+
+/// Print the earlier versions of a message as diffs against its current content
+fn print_edits(
+    weechat: &Weechat,
+    buffer: &Buffer,
+    msg: &Message,
+    n: parsing::MessageTarget,
+    guild: Option<GuildId>,
+) {
+    let revisions = crate::message_cache::revisions(msg.id);
+    if revisions.is_empty() {
+        plugin_print(&format!("No earlier versions of message {} are known", n));
+        return;
+    }
+    let opts = crate::printing::format_options(msg, guild);
+    let current = discord::formatting::discord_to_plain(weechat, &msg.content, opts);
+
+    let (red, green, reset) = (
+        weechat.color("red"),
+        weechat.color("green"),
+        weechat.color("reset"),
+    );
+    buffer.print(&format!(
+        "{}\tEarlier versions of message {} (- before, + now):",
+        weechat.get_prefix("network"),
+        n
+    ));
+    for revision in revisions {
+        let time = revision.timestamp.with_timezone(&chrono::Local);
+        buffer.print(&format!("\t{}", time.format("%Y-%m-%d %H:%M:%S")));
+        let before = discord::formatting::discord_to_plain(weechat, &revision.content, opts);
+        for line in parsing::diff::diff_lines(&before, &current) {
+            let line = match line {
+                parsing::diff::DiffLine::Unchanged(line) => format!("  {}", line),
+                parsing::diff::DiffLine::Removed(line) => format!("{}- {}{}", red, line, reset),
+                parsing::diff::DiffLine::Added(line) => format!("{}+ {}{}", green, line, reset),
+            };
+            buffer.print(&format!("\t  {}", line));
+        }
     }
 }
 
+fn react(args: Args, buffer: &Buffer, remove: bool) {
+    if args.args.len() < 2 {
+        plugin_print(&format!(
+            "{} requires a message number and an emoji",
+            args.base
+        ));
+        return;
+    }
+    let n = match parsing::parse_message_arg(args.args[0]) {
+        Some(n) => n,
+        None => {
+            plugin_print(&format!("Invalid message number \"{}\"", args.args[0]));
+            return;
+        }
+    };
+
+    let channel = match buffer.get_localvar("channelid") {
+        Some(channel) => channel,
+        None => return,
+    };
+    let channel = match channel.parse::<u64>() {
+        Ok(v) => ChannelId(v),
+        Err(_) => return,
+    };
+    let guild = buffer
+        .get_localvar("guildid")
+        .and_then(|id| id.parse().ok())
+        .map(GuildId);
+    let emoji = args.args[1].to_owned();
+
+    // Finding the nth message can take several requests
+    std::thread::spawn(move || {
+        let ctx = match crate::discord::get_ctx() {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        if let Err(e) = utils::react_to_message(ctx, channel, guild, n, &emoji, remove) {
+            plugin_print(&format!("An error occurred reacting to a message: {}", e));
+        }
+    });
+}
+
 // rust-lang/rust#52662 would let this api be improved by accepting option types
 fn format_option_change<'a, T: std::fmt::Display>(
     name: &str,
@@ -587,6 +1293,17 @@ Originally by https://github.com/khyperia/weechat-discord",
     noautostart
     token <token>
     upload <file>
+    reply <n> <message>
+    quote <n> [<message>]
+    edit [<n>|cancel]
+    history [<n>|all-since <YYYY-MM-DD>]
+    resend
+    multiline start|end
+    download <n> [<dir>]
+    reveal <n>
+    edits <n>
+    react <n> <emoji>
+    unreact <n> <emoji>
     me
     tableflip
     unflip
@@ -608,6 +1325,23 @@ Originally by https://github.com/khyperia/weechat-discord",
     status: set your Discord online status
     token: set Discord login token
     upload: upload a file to the current channel
+    reply: reply to the nth most recent message in the current channel
+    quote: send a message quoting the nth most recent message in the current channel
+    edit: load your nth most recent message into the input, so that pressing enter edits it. Clearing the input, edit cancel or weecord.main.edit_cancel_key cancels
+    history: load older messages into the current buffer, by count or back to a date
+    multiline: collect everything entered between start and end into one message
+    resend: retry sending messages in the current buffer that failed to send
+    download: save the attachments of the nth most recent message, to weecord.main.download_dir by default
+    reveal: show the spoilers in the nth most recent message when weecord.main.hide_spoilers is set
+    edits: show earlier versions of the nth most recent message seen since connecting, compared to its current content
+    react: add a reaction to the nth most recent message in the current channel
+    unreact: remove your reaction from the nth most recent message in the current channel
+
+Commands that take a message number <n> also accept #<id> to name a message by its id.
+
+In cursor mode, messages in Discord buffers can be acted on with r (reply), e (edit), d (delete your own
+message, pressed twice), + (react), l (copy a link to the message) and q (quote), and ctrl-clicking a message
+replies to it and ctrl-right-clicking reacts to it, unless weecord.main.cursor_keys is disabled.
 
 Examples:
   /discord token 123456789ABCDEF
@@ -615,6 +1349,8 @@ Examples:
   /discord autostart
   /discord disconnect
   /discord upload file.txt
+  /discord reply 2 I agree!
+  /discord react 1 👍
 ",
     completion:
 "connect || \
@@ -632,6 +1368,17 @@ noautostart || \
 status online|offline|invisible|idle|dnd || \
 game playing|listening|watching || \
 upload %(filename) || \
+reply || \
+edit cancel || \
+quote || \
+history all-since || \
+resend || \
+multiline start|end || \
+download || \
+reveal || \
+edits || \
+react || \
+unreact || \
 me || \
 tableflip || \
 unflip || \
@@ -1,7 +1,10 @@
-use weechat::{BooleanOption, ConfigOption, ConfigSectionInfo, StringOption, Weechat};
+use weechat::{
+    BooleanOption, ConfigOption, ConfigSectionInfo, IntegerOption, StringOption, Weechat,
+};
 
 use crate::utils;
 use crate::utils::GuildOrChannel;
+use serenity::model::id::GuildId;
 
 pub struct Config {
     pub token: StringOption,
@@ -12,6 +15,23 @@ pub struct Config {
     pub send_typing_events: BooleanOption,
     pub typing_messages: BooleanOption,
     pub irc_mode: BooleanOption,
+    pub history_size: IntegerOption,
+    pub message_overflow: StringOption,
+    pub download_dir: StringOption,
+    pub syntax_highlighting: BooleanOption,
+    pub syntax_colors: StringOption,
+    pub url_color: StringOption,
+    pub hide_spoilers: BooleanOption,
+    pub emoji_as_shortcodes: BooleanOption,
+    pub cursor_keys: BooleanOption,
+    pub edit_cancel_key: StringOption,
+    pub deleted_messages: IntegerOption,
+    pub highlight_words: StringOption,
+    pub everyone_highlights: StringOption,
+    pub nick_colors: StringOption,
+    pub group_messages: IntegerOption,
+    pub group_prefix: StringOption,
+    pub max_nicklist_members: IntegerOption,
     pub config: weechat::Config<()>,
 }
 
@@ -105,6 +125,205 @@ pub fn init(weechat: &Weechat) -> Config {
         None::<()>,
     );
 
+    let history_size = section.new_integer_option(
+        "history_size",
+        "Number of messages to load when a buffer is first opened",
+        "",
+        1,
+        100,
+        "25",
+        "25",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let message_overflow = section.new_string_option(
+        "message_overflow",
+        "What to do with messages longer than Discord allows: \"split\" them into several \
+         messages, or \"attach\" them as a text file",
+        "split",
+        "split",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let download_dir = section.new_string_option(
+        "download_dir",
+        "Directory attachments are saved to by /discord download when no directory is given",
+        "~/Downloads",
+        "~/Downloads",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let syntax_highlighting = section.new_boolean_option(
+        "syntax_highlighting",
+        "Highlight code blocks in rust, python, js/ts, json, sh, diff and toml",
+        true,
+        true,
+        false,
+        None,
+        None::<()>,
+    );
+
+    let syntax_colors = section.new_string_option(
+        "syntax_colors",
+        "Colors used to highlight code blocks, as a comma separated list of kind=color, where \
+         kind is one of keyword, string, comment, number, added or removed",
+        "keyword=magenta,string=green,comment=darkgray,number=cyan,added=green,removed=red",
+        "keyword=magenta,string=green,comment=darkgray,number=cyan,added=green,removed=red",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let url_color = section.new_string_option(
+        "url_color",
+        "Color used to display links",
+        "blue",
+        "blue",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let hide_spoilers = section.new_boolean_option(
+        "hide_spoilers",
+        "Hide the contents of spoilers until they are revealed with /discord reveal",
+        false,
+        false,
+        false,
+        None,
+        None::<()>,
+    );
+
+    let emoji_as_shortcodes = section.new_boolean_option(
+        "emoji_as_shortcodes",
+        "Show emoji in messages as :shortcodes:, for terminals that render emoji badly",
+        false,
+        false,
+        false,
+        None,
+        None::<()>,
+    );
+
+    let cursor_keys = section.new_boolean_option(
+        "cursor_keys",
+        "Bind keys to reply to, edit, delete, react to, link and quote messages in cursor mode, \
+         and ctrl-clicks to reply and react, unless they are already bound to something else",
+        true,
+        true,
+        false,
+        Some(|_: &mut (), option: &BooleanOption| {
+            // Reading the config runs this before the plugin exists, which binds the keys itself
+            if let Some(weecord) = unsafe { crate::__PLUGIN.as_ref() } {
+                crate::command::update_cursor_keys(weecord, option.value());
+            }
+        }),
+        Some(()),
+    );
+
+    let edit_cancel_key = section.new_string_option(
+        "edit_cancel_key",
+        "Key that cancels an edit started with /discord edit, bound only while editing and only \
+         if it isn't already bound (empty to not bind a key). Weechat can't bind escape on its \
+         own, as terminals send it as the start of other keys",
+        "meta-c",
+        "meta-c",
+        false,
+        None,
+        None::<()>,
+    );
+
+    // Weechat only accepts the listed values for an integer option with string values
+    let deleted_messages = section.new_integer_option(
+        "deleted_messages",
+        "How to show deleted messages: \"replace\" them with (deleted), or keep their content \
+         with a (deleted) suffix, either \"strikethrough\" or \"dim\"",
+        "replace|strikethrough|dim",
+        0,
+        0,
+        "replace",
+        "replace",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let highlight_words = section.new_string_option(
+        "highlight_words",
+        "Comma separated list of words that highlight you when they appear in a message",
+        "",
+        "",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let everyone_highlights = section.new_string_option(
+        "everyone_highlights",
+        "Comma separated list of guild ids where @everyone and @here highlight you, \"*\" for \
+         every guild, and \"!<guild id>\" to exclude a guild",
+        "*",
+        "*",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let nick_colors = section.new_string_option(
+        "nick_colors",
+        "How to color nicks in messages: \"role\" uses the color of the author's highest colored \
+         role, \"role_weechat\" falls back to weechat's nick colors for authors without one, \
+         \"weechat\" always uses weechat's nick colors and \"none\" doesn't color nicks",
+        "role_weechat",
+        "role_weechat",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let group_messages = section.new_integer_option(
+        "group_messages",
+        "Print messages sent by the same author within this many seconds of their previous \
+         message with weecord.main.group_prefix instead of their nick (0 to disable)",
+        "",
+        0,
+        24 * 60 * 60,
+        "0",
+        "0",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let group_prefix = section.new_string_option(
+        "group_prefix",
+        "Prefix of grouped messages, see weecord.main.group_messages",
+        "",
+        "",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let max_nicklist_members = section.new_integer_option(
+        "max_nicklist_members",
+        "Maximum number of members to load into the nicklist of a guild channel, the rest are \
+         summarized as \"+N more\" (0 for no limit)",
+        "",
+        0,
+        1_000_000,
+        "10000",
+        "10000",
+        false,
+        None,
+        None::<()>,
+    );
+
     config.read();
 
     Config {
@@ -116,10 +335,35 @@ pub fn init(weechat: &Weechat) -> Config {
         send_typing_events,
         typing_messages,
         irc_mode,
+        history_size,
+        message_overflow,
+        download_dir,
+        syntax_highlighting,
+        syntax_colors,
+        url_color,
+        hide_spoilers,
+        emoji_as_shortcodes,
+        cursor_keys,
+        edit_cancel_key,
+        deleted_messages,
+        highlight_words,
+        everyone_highlights,
+        nick_colors,
+        group_messages,
+        group_prefix,
+        max_nicklist_members,
         config,
     }
 }
 
+/// How deleted messages are shown, see weecord.main.deleted_messages
+#[derive(Clone, Copy, PartialEq)]
+pub enum DeletedMessages {
+    Replace,
+    Strikethrough,
+    Dim,
+}
+
 impl Config {
     pub fn autojoin_channels(&self) -> Vec<GuildOrChannel> {
         self.autojoin_channels
@@ -138,4 +382,34 @@ impl Config {
             .filter_map(utils::parse_id)
             .collect()
     }
+
+    pub fn highlight_words(&self) -> Vec<String> {
+        self.highlight_words
+            .value()
+            .split(',')
+            .map(str::trim)
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect()
+    }
+
+    pub fn deleted_messages(&self) -> DeletedMessages {
+        match self.deleted_messages.value() {
+            1 => DeletedMessages::Strikethrough,
+            2 => DeletedMessages::Dim,
+            _ => DeletedMessages::Replace,
+        }
+    }
+
+    /// Whether @everyone and @here should highlight you in a guild
+    pub fn everyone_highlights(&self, guild: GuildId) -> bool {
+        let value = self.everyone_highlights.value();
+        let entries: Vec<&str> = value.split(',').map(str::trim).collect();
+        let id = guild.0.to_string();
+
+        if entries.contains(&format!("!{}", id).as_str()) {
+            return false;
+        }
+        entries.iter().any(|&entry| entry == "*" || entry == id)
+    }
 }
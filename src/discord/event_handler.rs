@@ -1,11 +1,12 @@
-use crate::{buffers, on_main, on_main_blocking, printing, utils, Discord};
+use crate::config::DeletedMessages;
+use crate::{buffers, message_cache, on_main, on_main_blocking, printing, utils, Discord};
 use lazy_static::lazy_static;
 use serenity::{model::gateway::Ready, model::prelude::*, prelude::*};
+use std::collections::HashMap;
 use std::sync::{mpsc::Sender, Arc};
 use std::thread;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use weechat::hdata::{HData, HDataPointer};
-use weechat::{Buffer, ConfigOption, HasHData, Weechat};
+use weechat::{Buffer, ConfigOption, Weechat};
 
 const MAX_TYPING_EVENTS: usize = 50;
 
@@ -47,6 +48,8 @@ lazy_static! {
     pub static ref TYPING_EVENTS: Arc<Mutex<TypingTracker>> = Arc::new(Mutex::new(TypingTracker {
         entries: Vec::new(),
     }));
+    static ref REACTION_QUEUE: Mutex<crossbeam_channel::Sender<ReactionUpdate>> =
+        Mutex::new(start_reaction_worker());
 }
 
 pub struct Handler {
@@ -133,9 +136,32 @@ impl EventHandler for Handler {
         }
     }
 
-    fn guild_member_update(&self, ctx: Context, old: Option<Member>, new: Member) {
+    fn guild_member_addition(&self, _ctx: Context, guild_id: GuildId, new_member: Member) {
+        buffers::add_guild_members(guild_id, vec![new_member]);
+    }
+
+    fn guild_member_removal(
+        &self,
+        _ctx: Context,
+        guild_id: GuildId,
+        user: User,
+        _member: Option<Member>,
+    ) {
+        buffers::remove_guild_member(guild_id, user.id);
+    }
+
+    fn guild_members_chunk(
+        &self,
+        _ctx: Context,
+        guild_id: GuildId,
+        members: HashMap<UserId, Member>,
+    ) {
+        buffers::add_guild_members(guild_id, members.into_iter().map(|(_, m)| m).collect());
+    }
+
+    fn guild_member_update(&self, ctx: Context, _old: Option<Member>, new: Member) {
         thread::spawn(move || {
-            buffers::update_member_nick(&old, &new);
+            buffers::update_guild_member(&new);
             if ctx.cache.read().user.id == new.user_id() {
                 buffers::update_nick();
             }
@@ -146,12 +172,12 @@ impl EventHandler for Handler {
         let string_channel = utils::buffer_id_for_channel(msg.guild_id, msg.channel_id);
         let () = on_main_blocking(move |weechat| {
             if let Some(buffer) = weechat.buffer_search("weecord", &string_channel) {
-                print_message(&weechat, ctx, &msg, &buffer);
+                print_message(&weechat, &ctx, &msg, &buffer);
             } else {
                 match msg.channel_id.to_channel(&ctx) {
                     chan @ Ok(Channel::Private(_)) => {
                         if let Some(buffer) = weechat.buffer_search("weecord", &string_channel) {
-                            print_message(&weechat, ctx, &msg, &buffer);
+                            print_message(&weechat, &ctx, &msg, &buffer);
                         } else {
                             buffers::create_buffer_from_dm(
                                 &ctx.cache,
@@ -164,7 +190,7 @@ impl EventHandler for Handler {
                     }
                     chan @ Ok(Channel::Group(_)) => {
                         if let Some(buffer) = weechat.buffer_search("weecord", &string_channel) {
-                            print_message(&weechat, ctx, &msg, &buffer);
+                            print_message(&weechat, &ctx, &msg, &buffer);
                         } else {
                             buffers::create_buffer_from_group(
                                 &ctx.cache,
@@ -253,19 +279,19 @@ impl EventHandler for Handler {
                     _ => return,
                 };
 
-                let mut msgs = match event
-                    .channel_id
-                    .messages(ctx, |retriever| retriever.limit(1).around(event.id))
-                {
-                    Ok(msg) => msg,
-                    _ => {
-                        return;
-                    }
-                };
+                let query = format!("?limit=1&around={}", event.id);
+                let mut msgs =
+                    match message_cache::fetch_messages(&ctx.http, event.channel_id, &query) {
+                        Ok(msg) => msg,
+                        _ => {
+                            return;
+                        }
+                    };
                 let msg = match msgs.pop() {
                     Some(msg) => msg,
                     None => return,
                 };
+                message_cache::insert(&msg);
 
                 on_main(move |weecord| {
                     let ctx = match crate::discord::get_ctx() {
@@ -282,6 +308,27 @@ impl EventHandler for Handler {
         }
     }
 
+    fn reaction_add(&self, ctx: Context, add_reaction: Reaction) {
+        update_reactions(&ctx, add_reaction, true);
+    }
+
+    fn reaction_remove(&self, ctx: Context, removed_reaction: Reaction) {
+        update_reactions(&ctx, removed_reaction, false);
+    }
+
+    fn reaction_remove_all(
+        &self,
+        ctx: Context,
+        channel_id: ChannelId,
+        removed_from_message_id: MessageId,
+    ) {
+        if let Some(mut msg) = message_cache::get(channel_id, removed_from_message_id) {
+            msg.reactions.clear();
+            message_cache::insert(&msg);
+            rerender_message(&ctx, msg);
+        }
+    }
+
     fn ready(&self, ctx: Context, ready: Ready) {
         // Cache seems not to have all fields properly populated
 
@@ -315,6 +362,13 @@ impl EventHandler for Handler {
             crate::discord::CONTEXT = Some(ctx);
         }
         let _ = self.sender.lock().send(ready);
+
+        // Ready is also received when the gateway reconnects
+        thread::spawn(backfill_missed_messages);
+    }
+
+    fn resume(&self, _ctx: Context, _: ResumedEvent) {
+        thread::spawn(backfill_missed_messages);
     }
 
     fn typing_start(&self, ctx: Context, event: TypingStartEvent) {
@@ -382,30 +436,272 @@ impl EventHandler for Handler {
 }
 
 fn delete_message(ctx: &Context, channel_id: ChannelId, deleted_message_id: MessageId) {
-    if let Some(channel) = ctx.cache.read().channels.get(&channel_id) {
-        let guild_id = channel.read().guild_id;
-        let buffer_name = utils::buffer_id_for_channel(Some(guild_id), channel_id);
-
-        on_main(move |weecord| {
-            modify_buffer_lines(
-                weecord,
-                deleted_message_id,
-                buffer_name,
-                format!(
-                    "{}(deleted){}",
-                    weecord.color("red"),
-                    weecord.color("reset")
-                ),
-            );
+    let guild_id = ctx
+        .cache
+        .read()
+        .channels
+        .get(&channel_id)
+        .map(|channel| channel.read().guild_id);
+
+    // The config can only be read on the main thread, so decide there
+    on_main(move |weecord| {
+        if weecord.config.deleted_messages() != DeletedMessages::Replace {
+            if let (Some(ctx), Some(msg)) = (
+                crate::discord::get_ctx(),
+                message_cache::get(channel_id, deleted_message_id),
+            ) {
+                message_cache::mark_deleted(deleted_message_id);
+                rerender_message(ctx, msg);
+                return;
+            }
+        }
+
+        message_cache::remove(channel_id, deleted_message_id);
+        modify_buffer_lines(
+            weecord,
+            deleted_message_id,
+            utils::buffer_id_for_channel(guild_id, channel_id),
+            format!(
+                "{}(deleted){}",
+                weecord.color("red"),
+                weecord.color("reset")
+            ),
+        );
+    });
+}
+
+/// A reaction being added or removed, waiting to be applied to its message
+struct ReactionUpdate {
+    reaction: Reaction,
+    buffer_name: String,
+    is_self: bool,
+    added: bool,
+}
+
+fn update_reactions(ctx: &Context, reaction: Reaction, added: bool) {
+    let is_self = reaction.user_id == ctx.cache.read().user.id;
+    let guild_id = ctx
+        .cache
+        .read()
+        .channels
+        .get(&reaction.channel_id)
+        .map(|channel| channel.read().guild_id);
+    let update = ReactionUpdate {
+        buffer_name: utils::buffer_id_for_channel(guild_id, reaction.channel_id),
+        reaction,
+        is_self,
+        added,
+    };
+
+    // Nothing is shown for channels without an open buffer, so there is nothing to fetch
+    on_main(move |weechat| {
+        let loaded = weechat
+            .buffer_search("weecord", &update.buffer_name)
+            .map_or(false, |buffer| {
+                buffer.get_localvar("loaded_history").is_some()
+            });
+        if loaded {
+            let _ = REACTION_QUEUE.lock().send(update);
+        }
+    });
+}
+
+/// Apply reaction updates one at a time and in order, so that counts aren't lost to races
+fn start_reaction_worker() -> crossbeam_channel::Sender<ReactionUpdate> {
+    let (tx, rx) = crossbeam_channel::unbounded::<ReactionUpdate>();
+
+    thread::Builder::new()
+        .name("Reaction updater".into())
+        .spawn(move || {
+            for update in rx {
+                apply_reaction_update(update);
+            }
+        })
+        .expect("Unable to name thread");
+
+    tx
+}
+
+fn apply_reaction_update(update: ReactionUpdate) {
+    let ctx = match crate::discord::get_ctx() {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+    let ReactionUpdate {
+        reaction,
+        buffer_name,
+        is_self,
+        added,
+    } = update;
+
+    // A freshly fetched message already reflects this reaction
+    let msg = match message_cache::get(reaction.channel_id, reaction.message_id) {
+        Some(mut msg) => {
+            apply_reaction(&mut msg, &reaction.emoji, is_self, added);
+            message_cache::insert(&msg);
+            msg
+        }
+        None => {
+            match message_cache::fetch_message(&ctx.http, reaction.channel_id, reaction.message_id)
+            {
+                Ok(msg) => {
+                    message_cache::insert(&msg);
+                    msg
+                }
+                Err(_) => return,
+            }
+        }
+    };
+
+    on_main(move |weechat| {
+        if let Some(buffer) = weechat.buffer_search("weecord", &buffer_name) {
+            if buffer.get_localvar("loaded_history").is_some() {
+                printing::update_reactions(weechat, &buffer, &msg);
+            }
+        }
+    });
+}
+
+fn apply_reaction(msg: &mut Message, emoji: &ReactionType, is_self: bool, added: bool) {
+    match msg.reactions.iter().position(|r| &r.reaction_type == emoji) {
+        Some(pos) => {
+            let reaction = &mut msg.reactions[pos];
+            if added {
+                reaction.count += 1;
+            } else {
+                reaction.count = reaction.count.saturating_sub(1);
+            }
+            if is_self {
+                reaction.me = added;
+            }
+            if reaction.count == 0 {
+                msg.reactions.remove(pos);
+            }
+        }
+        None if added => msg.reactions.push(MessageReaction {
+            count: 1,
+            me: is_self,
+            reaction_type: emoji.clone(),
+        }),
+        None => {}
+    }
+}
+
+/// Re-render a message and update its lines in place
+pub(crate) fn rerender_message(ctx: &Context, msg: Message) {
+    let guild_id = ctx
+        .cache
+        .read()
+        .channels
+        .get(&msg.channel_id)
+        .map(|channel| channel.read().guild_id);
+    let buffer_name = utils::buffer_id_for_channel(guild_id, msg.channel_id);
+
+    on_main(move |weecord| {
+        let ctx = match crate::discord::get_ctx() {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+
+        let (_, new_content) = printing::render_msg(&ctx.cache, weecord, &msg, guild_id);
+
+        modify_buffer_lines(weecord, msg.id, buffer_name, new_content);
+    });
+}
+
+/// Fetch and print any messages sent to open buffers after the newest message they display
+fn backfill_missed_messages() {
+    let ctx = match crate::discord::get_ctx() {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+
+    let channels: Vec<(Option<GuildId>, ChannelId)> = {
+        let cache = ctx.cache.read();
+        cache
+            .channels
+            .values()
+            .map(|channel| {
+                let channel = channel.read();
+                (Some(channel.guild_id), channel.id)
+            })
+            .chain(cache.private_channels.keys().map(|id| (None, *id)))
+            .chain(cache.groups.keys().map(|id| (None, *id)))
+            .collect()
+    };
+
+    let open_channels: Vec<(String, ChannelId, MessageId)> = on_main_blocking(|weecord| {
+        channels
+            .iter()
+            .filter_map(|&(guild_id, channel_id)| {
+                let buffer_name = utils::buffer_id_for_channel(guild_id, channel_id);
+                let buffer = weecord.buffer_search("weecord", &buffer_name)?;
+                let newest = buffer.get_localvar("newest_msgid")?.parse().ok()?;
+                Some((buffer_name, channel_id, MessageId(newest)))
+            })
+            .collect()
+    });
+
+    for (buffer_name, channel_id, newest) in open_channels {
+        let mut after = newest;
+        let mut missed = Vec::new();
+        loop {
+            let query = format!("?limit=100&after={}", after);
+            let page = match message_cache::fetch_messages(&ctx.http, channel_id, &query) {
+                Ok(page) => page,
+                Err(_) => break,
+            };
+            let page_len = page.len();
+            missed.extend(page);
+            match missed.iter().map(|msg| msg.id).max() {
+                Some(id) => after = id,
+                None => break,
+            }
+            if page_len < 100 {
+                break;
+            }
+        }
+
+        if missed.is_empty() {
+            continue;
+        }
+
+        on_main(move |weechat| {
+            let buffer = match weechat.buffer_search("weecord", &buffer_name) {
+                Some(buffer) => buffer,
+                None => return,
+            };
+            // Live messages may have been printed while these were fetched, so they are inserted by
+            // id, still notifying for missed mentions and private messages
+            let missed: Vec<_> = missed
+                .into_iter()
+                .filter(|msg| !crate::discord::send_queue::confirm(weechat, &buffer, msg))
+                .collect();
+            let notify = !utils::buffer_is_muted(&buffer);
+            printing::insert_messages(weechat, &buffer, &missed, notify);
         });
     }
 }
 
-fn print_message(weechat: &Weechat, ctx: Context, msg: &Message, buffer: &Buffer) {
+fn print_message(weechat: &Weechat, ctx: &Context, msg: &Message, buffer: &Buffer) {
+    if crate::discord::send_queue::confirm(weechat, buffer, msg) {
+        return;
+    }
+
     let muted = utils::buffer_is_muted(&buffer);
-    let notify = !msg.is_own(ctx.cache) && !muted;
+    let notify = msg.author.id != ctx.cache.read().user.id && !muted;
+    // The same message can arrive from the gateway, history loading and backfilling, and messages
+    // can arrive out of order. Only a message older than the newest printed one can be either, so
+    // it is inserted in its place, unless it's already printed
+    let newest = buffer
+        .get_localvar("newest_msgid")
+        .and_then(|id| id.parse::<u64>().ok())
+        .map(MessageId);
+    if newest.map_or(false, |newest| msg.id <= newest) {
+        printing::insert_messages(weechat, buffer, std::slice::from_ref(msg), notify);
+        return;
+    }
     printing::print_msg(&weechat, &buffer, &msg, notify);
-    printing::inject_msg_id(msg.id, buffer);
 }
 
 fn print_guild_status_message(guild_id: GuildId, msg: &str) {
@@ -420,7 +716,7 @@ fn print_guild_status_message(guild_id: GuildId, msg: &str) {
     })
 }
 
-fn modify_buffer_lines(
+pub(crate) fn modify_buffer_lines(
     weecord: &Discord,
     message_id: MessageId,
     buffer_name: String,
@@ -434,54 +730,6 @@ fn modify_buffer_lines(
         return;
     }
 
-    let buffer_hdata = buffer.get_hdata("buffer").unwrap();
-    let lines_ptr: HDataPointer = buffer_hdata.get_var("own_lines").unwrap();
-    let lines_hdata = lines_ptr.get_hdata("lines").unwrap();
-    let mut maybe_last_line_ptr = lines_hdata.get_var::<HDataPointer>("last_line");
-
-    let mut pointers = Vec::new();
-
-    fn get_msg_id(last_line_hdata: &HData) -> u64 {
-        let line_data_ptr: HDataPointer = last_line_hdata.get_var("data").unwrap();
-
-        let line_data_hdata = line_data_ptr.get_hdata("line_data").unwrap();
-
-        unsafe { line_data_hdata.get_i64_unchecked("date_printed") as u64 }
-    }
-
-    // advance to the edited message
-    while let Some(last_line_ptr) = &maybe_last_line_ptr {
-        let last_line_hdata = last_line_ptr.get_hdata("line").unwrap();
-
-        if get_msg_id(&last_line_hdata) == message_id.0 {
-            break;
-        }
-
-        maybe_last_line_ptr = last_line_ptr.advance(&last_line_hdata, -1);
-    }
-
-    // collect all lines of the message
-    while let Some(last_line_ptr) = maybe_last_line_ptr {
-        let last_line_hdata = last_line_ptr.get_hdata("line").unwrap();
-
-        if get_msg_id(&last_line_hdata) != message_id.0 {
-            break;
-        }
-
-        let line_data_ptr: HDataPointer = last_line_hdata.get_var("data").unwrap();
-
-        let line_data_hdata = line_data_ptr.get_hdata("line_data").unwrap();
-
-        pointers.push(line_data_hdata);
-
-        maybe_last_line_ptr = last_line_ptr.advance(&last_line_hdata, -1);
-    }
-
-    let new_lines = new_content.splitn(pointers.len(), "\n");
-    let new_lines = new_lines.map(|l| l.replace("\n", " | "));
-    let new_lines = new_lines.chain(std::iter::repeat("".to_owned()));
-
-    for (line_ptr, new_line) in pointers.iter().rev().zip(new_lines) {
-        line_ptr.update_var("message", new_line);
-    }
+    let lines = printing::find_message_lines(&buffer, message_id);
+    printing::rewrite_lines(&lines, &new_content);
 }
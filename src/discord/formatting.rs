@@ -1,93 +1,366 @@
-use parsing::{self, MarkdownNode};
-use std::rc::Rc;
-use std::sync::RwLock;
+use crate::utils;
+use chrono::{Local, TimeZone};
+use parsing::{self, highlight::TokenKind, Node};
+use serenity::model::prelude::*;
+use std::collections::HashMap;
 use weechat::Weechat;
 
-pub fn discord_to_weechat(weechat: &Weechat, msg: &str) -> String {
-    let ast = parsing::parse_markdown(msg);
+/// Options that change how a message is formatted
+#[derive(Clone, Copy, Default)]
+pub struct FormatOptions<'a> {
+    /// The guild the message was sent in, used to look up nicknames
+    pub guild: Option<GuildId>,
+    /// Show the contents of spoilers even if weecord.main.hide_spoilers is set
+    pub show_spoilers: bool,
+    /// The users the message mentions, to name mentioned users that aren't cached
+    pub mentions: &'a [User],
+}
+
+pub fn discord_to_weechat(weechat: &Weechat, msg: &str, opts: FormatOptions) -> String {
+    render_nodes(weechat, opts, &parsing::parse_message(msg))
+}
+
+/// Render a message as text without any formatting, for previews and other dimmed text
+///
+/// Spoilers are hidden just as they are by `discord_to_weechat`
+pub fn discord_to_plain(weechat: &Weechat, msg: &str, opts: FormatOptions) -> String {
+    plain_text(weechat, opts, &parsing::parse_message(msg))
+}
+
+/// Whether spoilers are shown, rather than hidden until revealed
+fn show_spoilers(weechat: &Weechat, opts: FormatOptions) -> bool {
+    opts.show_spoilers || !crate::upgrade_plugin(weechat).config.hide_spoilers.value()
+}
+
+fn render_nodes(weechat: &Weechat, opts: FormatOptions, nodes: &[Node]) -> String {
+    let ctx = crate::discord::get_ctx();
+    let emoji_as_shortcodes = crate::upgrade_plugin(weechat)
+        .config
+        .emoji_as_shortcodes
+        .value();
 
     let mut out = String::new();
-    for node in &ast.0 {
-        out.push_str(&discord_to_weechat_reducer(
-            &weechat,
-            &*node.read().unwrap(),
-        ))
+    for node in nodes {
+        let rendered = match (node, ctx) {
+            (Node::Text(text), _) if emoji_as_shortcodes => {
+                parsing::emoji::replace_emoji_with_shortcodes(text)
+            }
+            (Node::Text(text), _) => text.to_owned(),
+            (Node::Bold(nodes), _) => format!(
+                "{}{}{}",
+                weechat.color("bold"),
+                render_nodes(weechat, opts, nodes),
+                weechat.color("-bold")
+            ),
+            (Node::Italic(nodes), _) => format!(
+                "{}{}{}",
+                weechat.color("italic"),
+                render_nodes(weechat, opts, nodes),
+                weechat.color("-italic")
+            ),
+            (Node::Underline(nodes), _) => format!(
+                "{}{}{}",
+                weechat.color("underline"),
+                render_nodes(weechat, opts, nodes),
+                weechat.color("-underline")
+            ),
+            (Node::Strikethrough(nodes), _) => format!(
+                "{}~~{}~~{}",
+                weechat.color("red"),
+                render_nodes(weechat, opts, nodes),
+                weechat.color("-red")
+            ),
+            (Node::Spoiler(nodes), _) => {
+                if show_spoilers(weechat, opts) {
+                    format!(
+                        "{}||{}||{}",
+                        weechat.color("italic"),
+                        render_nodes(weechat, opts, nodes),
+                        weechat.color("-italic")
+                    )
+                } else {
+                    format!(
+                        "{}||{}||{}",
+                        weechat.color("8"),
+                        hide_spoiler(weechat, opts, nodes),
+                        weechat.color("resetcolor")
+                    )
+                }
+            }
+            (Node::InlineCode(code), _) => {
+                format!("{}{}{}", weechat.color("*8"), code, weechat.color("reset"))
+            }
+            (Node::Code(language, code), _) => format!(
+                "```{}\n{}\n```",
+                language,
+                highlight_code(weechat, language, code)
+            ),
+            (Node::BlockQuote(nodes), _) => render_nodes(weechat, opts, nodes)
+                .lines()
+                .fold(String::new(), |acc, x| format!("{}\n▎{}", acc, x)),
+            (Node::SingleBlockQuote(nodes), _) => render_nodes(weechat, opts, nodes)
+                .lines()
+                .fold(String::new(), |acc, x| {
+                    format!("{}▎{}\n", acc, strip_leading_bracket(x))
+                }),
+            (Node::UserMention(id), Some(ctx)) => {
+                render_user_mention(weechat, &ctx.cache, opts, UserId(*id))
+            }
+            (Node::RoleMention(id), Some(ctx)) => {
+                render_role_mention(weechat, &ctx.cache, RoleId(*id))
+            }
+            (Node::ChannelMention(id), Some(ctx)) => format!(
+                "{}#{}{}",
+                weechat.color("chat_channel"),
+                channel_name(&ctx.cache, ChannelId(*id)),
+                weechat.color("resetcolor")
+            ),
+            (Node::UserMention(_), None) => "@unknown-user".into(),
+            (Node::RoleMention(_), None) => "@unknown-role".into(),
+            (Node::ChannelMention(_), None) => "#unknown-channel".into(),
+            (Node::Emoji { name, .. }, _) => format!(":{}:", name),
+            (Node::MaskedLink { text, url }, _) => {
+                format!("{} <{}>", text, render_url(weechat, url))
+            }
+            (Node::Url(url), _) => render_url(weechat, url),
+            (Node::Timestamp { unix, style }, _) => format!(
+                "{}{}{}",
+                weechat.color("underline"),
+                format_timestamp(*unix, *style),
+                weechat.color("-underline")
+            ),
+        };
+        out.push_str(&rendered);
     }
     out
 }
 
-fn collect_styles(weechat: &Weechat, styles: &[Rc<RwLock<MarkdownNode>>]) -> String {
-    styles
+/// The text of some nodes, without any formatting
+fn plain_text(weechat: &Weechat, opts: FormatOptions, nodes: &[Node]) -> String {
+    let ctx = crate::discord::get_ctx();
+    nodes
         .iter()
-        .map(|s| discord_to_weechat_reducer(&weechat, &*s.read().unwrap()))
-        .collect::<Vec<_>>()
-        .join("")
+        .map(|node| match (node, ctx) {
+            (Node::Spoiler(nodes), _) if show_spoilers(weechat, opts) => {
+                format!("||{}||", plain_text(weechat, opts, nodes))
+            }
+            (Node::Spoiler(nodes), _) => format!("||{}||", hide_spoiler(weechat, opts, nodes)),
+            (Node::Bold(nodes), _)
+            | (Node::Italic(nodes), _)
+            | (Node::Underline(nodes), _)
+            | (Node::Strikethrough(nodes), _)
+            | (Node::BlockQuote(nodes), _)
+            | (Node::SingleBlockQuote(nodes), _) => plain_text(weechat, opts, nodes),
+            (Node::Text(text), _) | (Node::InlineCode(text), _) | (Node::Code(_, text), _) => {
+                text.clone()
+            }
+            (Node::UserMention(id), Some(ctx)) => {
+                format!("@{}", user_mention_name(&ctx.cache, opts, UserId(*id)))
+            }
+            (Node::RoleMention(id), Some(ctx)) => match RoleId(*id).to_role_cached(&ctx.cache) {
+                Some(role) => format!("@{}", role.name),
+                None => "@unknown-role".into(),
+            },
+            (Node::ChannelMention(id), Some(ctx)) => {
+                format!("#{}", channel_name(&ctx.cache, ChannelId(*id)))
+            }
+            (Node::UserMention(_), None) => "@unknown-user".into(),
+            (Node::RoleMention(_), None) => "@unknown-role".into(),
+            (Node::ChannelMention(_), None) => "#unknown-channel".into(),
+            (Node::Emoji { name, .. }, _) => format!(":{}:", name),
+            (Node::Timestamp { unix, style }, _) => format_timestamp(*unix, *style),
+            (Node::MaskedLink { text, url }, _) => format!("{} <{}>", text, url),
+            (Node::Url(url), _) => url.clone(),
+        })
+        .collect()
 }
 
-// TODO: Spoilers, code syntax highlighting?
-fn discord_to_weechat_reducer(weechat: &Weechat, node: &MarkdownNode) -> String {
-    use MarkdownNode::*;
-    match node {
-        Bold(styles) => format!(
-            "{}{}{}",
-            weechat.color("bold"),
-            collect_styles(weechat, styles),
-            weechat.color("-bold")
-        ),
-        Italic(styles) => format!(
-            "{}{}{}",
-            weechat.color("italic"),
-            collect_styles(weechat, styles),
-            weechat.color("-italic")
-        ),
-        Underline(styles) => format!(
-            "{}{}{}",
-            weechat.color("underline"),
-            collect_styles(weechat, styles),
-            weechat.color("-underline")
-        ),
-        Strikethrough(styles) => format!(
-            "{}~~{}~~{}",
-            weechat.color("red"),
-            collect_styles(weechat, styles),
-            weechat.color("-red")
-        ),
-        Spoiler(styles) => format!(
-            "{}||{}||{}",
-            weechat.color("italic"),
-            collect_styles(weechat, styles),
-            weechat.color("-italic")
-        ),
-        Text(string) => string.to_owned(),
-        InlineCode(string) => format!(
-            "{}{}{}",
-            weechat.color("*8"),
-            string,
-            weechat.color("reset")
-        ),
-        Code(language, text) => {
-            let (fmt, reset) = (weechat.color("*8"), weechat.color("reset"));
+/// Blocks the length of a hidden spoiler's text
+fn hide_spoiler(weechat: &Weechat, opts: FormatOptions, nodes: &[Node]) -> String {
+    "█".repeat(plain_text(weechat, opts, nodes).chars().count())
+}
 
-            format!(
-                "```{}\n{}\n```",
-                language,
-                text.lines()
-                    .map(|l| format!("{}{}{}", fmt, l, reset))
-                    .collect::<Vec<_>>()
-                    .join("\n"),
-            )
-        }
-        BlockQuote(styles) => collect_styles(weechat, styles)
-            .lines()
-            .fold(String::new(), |acc, x| format!("{}\n▎{}", acc, x)),
-        SingleBlockQuote(styles) => collect_styles(weechat, styles)
-            .lines()
-            .fold(String::new(), |acc, x| {
-                format!("{}▎{}\n", acc, strip_leading_bracket(x))
-            }),
+fn highlight_code(weechat: &Weechat, language: &str, code: &str) -> String {
+    let (plain, reset) = (weechat.color("*8"), weechat.color("reset"));
+    let config = &crate::upgrade_plugin(weechat).config;
+
+    let tokens = if config.syntax_highlighting.value() {
+        parsing::highlight::highlight(language, code)
+    } else {
+        None
+    };
+    let tokens = tokens.unwrap_or_else(|| vec![(TokenKind::Plain, code)]);
+    let colors = syntax_colors(&config.syntax_colors.value());
+
+    let mut out = String::new();
+    for (kind, text) in tokens {
+        let color = match colors.get(&kind) {
+            Some(color) => weechat.color(color),
+            None => plain.clone(),
+        };
+        // Weechat doesn't carry colors over to the next line, so every line has to be colored
+        let lines = text
+            .split('\n')
+            .map(|line| {
+                if line.is_empty() {
+                    String::new()
+                } else {
+                    format!("{}{}{}", color, line, reset)
+                }
+            })
+            .collect::<Vec<_>>();
+        out.push_str(&lines.join("\n"));
     }
+    out
+}
+
+/// Parse the syntax_colors option, a list of `kind=color`
+fn syntax_colors(option: &str) -> HashMap<TokenKind, String> {
+    option
+        .split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let kind = match parts.next()?.trim() {
+                "keyword" => TokenKind::Keyword,
+                "string" => TokenKind::String,
+                "comment" => TokenKind::Comment,
+                "number" => TokenKind::Number,
+                "added" => TokenKind::Added,
+                "removed" => TokenKind::Removed,
+                _ => return None,
+            };
+            Some((kind, parts.next()?.trim().to_owned()))
+        })
+        .collect()
 }
 
 fn strip_leading_bracket(line: &str) -> &str {
     &line[line.find("> ").map(|x| x + 2).unwrap_or(0)..]
 }
+
+/// Color a url, leaving the url itself untouched so that url grabbing scripts can find it
+fn render_url(weechat: &Weechat, url: &str) -> String {
+    let color = crate::upgrade_plugin(weechat).config.url_color.value();
+    format!(
+        "{}{}{}",
+        weechat.color(&color),
+        url,
+        weechat.color("resetcolor")
+    )
+}
+
+fn render_user_mention(
+    weechat: &Weechat,
+    cache: &serenity::cache::CacheRwLock,
+    opts: FormatOptions,
+    user: UserId,
+) -> String {
+    let name = user_mention_name(cache, opts, user);
+    if user == cache.read().user.id {
+        format!(
+            "{}@{}{}",
+            weechat.color("chat_highlight"),
+            name,
+            weechat.color("resetcolor")
+        )
+    } else {
+        format!(
+            "{}@{}{}",
+            weechat.color("bold"),
+            name,
+            weechat.color("-bold")
+        )
+    }
+}
+
+fn user_mention_name(
+    cache: &serenity::cache::CacheRwLock,
+    opts: FormatOptions,
+    user: UserId,
+) -> String {
+    opts.guild
+        .and_then(|guild| cache.read().member(guild, user))
+        .map(|member| member.display_name().to_string())
+        .or_else(|| {
+            user.to_user_cached(cache)
+                .map(|user| user.read().name.clone())
+        })
+        .or_else(|| {
+            opts.mentions
+                .iter()
+                .find(|mentioned| mentioned.id == user)
+                .map(|mentioned| mentioned.name.clone())
+        })
+        .unwrap_or_else(|| "unknown-user".into())
+}
+
+fn channel_name(cache: &serenity::cache::CacheRwLock, channel: ChannelId) -> String {
+    channel
+        .name(cache)
+        .unwrap_or_else(|| "unknown-channel".into())
+}
+
+fn render_role_mention(
+    weechat: &Weechat,
+    cache: &serenity::cache::CacheRwLock,
+    role: RoleId,
+) -> String {
+    match role.to_role_cached(cache) {
+        // Roles without a colour have a colour of 0
+        Some(role) if role.colour.0 != 0 => format!(
+            "{}@{}{}",
+            weechat.color(&utils::color_name(weechat, role.colour)),
+            role.name,
+            weechat.color("resetcolor")
+        ),
+        Some(role) => format!("@{}", role.name),
+        None => "@unknown-role".into(),
+    }
+}
+
+/// Format a timestamp like the official client does for each `<t:unix:style>` style
+fn format_timestamp(unix: i64, style: Option<char>) -> String {
+    let time = match Local.timestamp_opt(unix, 0).single() {
+        Some(time) => time,
+        None => return format!("<t:{}>", unix),
+    };
+    let format = match style {
+        Some('t') => "%H:%M",
+        Some('T') => "%H:%M:%S",
+        Some('d') => "%d/%m/%Y",
+        Some('D') => "%e %B %Y",
+        Some('F') => "%A, %e %B %Y %H:%M",
+        Some('R') => return relative_time(unix - Local::now().timestamp()),
+        _ => "%e %B %Y %H:%M",
+    };
+    time.format(format).to_string().trim_start().to_owned()
+}
+
+/// Describe an offset from now in seconds, e.g. "3 hours ago" or "in 2 days"
+fn relative_time(offset: i64) -> String {
+    const UNITS: [(&str, i64); 6] = [
+        ("year", 365 * 24 * 60 * 60),
+        ("month", 30 * 24 * 60 * 60),
+        ("day", 24 * 60 * 60),
+        ("hour", 60 * 60),
+        ("minute", 60),
+        ("second", 1),
+    ];
+
+    if offset == 0 {
+        return "now".to_owned();
+    }
+    let seconds = offset.abs();
+    let (unit, size) = UNITS
+        .iter()
+        .find(|(_, size)| seconds >= *size)
+        .unwrap_or(&UNITS[UNITS.len() - 1]);
+    let count = seconds / size;
+    let plural = if count == 1 { "" } else { "s" };
+
+    if offset < 0 {
+        format!("{} {}{} ago", count, unit, plural)
+    } else {
+        format!("in {} {}{}", count, unit, plural)
+    }
+}
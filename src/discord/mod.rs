@@ -5,8 +5,9 @@ use serenity::{client::Context, prelude::Mutex};
 use std::{sync::Arc, thread};
 
 mod client;
-mod event_handler;
+pub(crate) mod event_handler;
 pub mod formatting;
+pub mod send_queue;
 
 pub use event_handler::TYPING_EVENTS;
 
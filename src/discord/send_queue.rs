@@ -0,0 +1,394 @@
+use crate::discord::formatting::{self, FormatOptions};
+use crate::{message_cache, on_main, printing, utils};
+use crossbeam_channel::{unbounded, Sender};
+use lazy_static::lazy_static;
+use serenity::{http::HttpError, model::prelude::*, prelude::*};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use weechat::{Buffer, Weechat};
+
+const MAX_SEND_ATTEMPTS: u32 = 3;
+/// How long to wait for the gateway to echo a message whose send ended in an ambiguous error
+const ECHO_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub enum Attachment {
+    /// A file on disk, by its full path
+    File(String),
+    /// Text too long for a message
+    Text { content: String, filename: String },
+}
+
+impl Attachment {
+    fn filename(&self) -> &str {
+        match self {
+            Attachment::File(path) => std::path::Path::new(path)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or(path),
+            Attachment::Text { filename, .. } => filename,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct OutgoingMessage {
+    buffer_name: String,
+    channel_id: ChannelId,
+    content: String,
+    attachment: Option<Attachment>,
+    /// The message this one replies to
+    reply_to: Option<MessageId>,
+    nonce: String,
+}
+
+impl OutgoingMessage {
+    /// The text shown in the local echo
+    fn echo(&self) -> String {
+        match &self.attachment {
+            Some(attachment) if self.content.is_empty() => {
+                format!("[uploading {}]", attachment.filename())
+            }
+            Some(attachment) => format!("{}\n[uploading {}]", self.content, attachment.filename()),
+            None => self.content.clone(),
+        }
+    }
+}
+
+lazy_static! {
+    static ref QUEUE: Mutex<Sender<OutgoingMessage>> = Mutex::new(start_worker());
+    /// Messages that have been queued but not yet echoed back by Discord, by nonce
+    static ref PENDING: Mutex<HashMap<String, OutgoingMessage>> = Mutex::new(HashMap::new());
+    /// Messages that could not be sent, by nonce
+    static ref FAILED: Mutex<HashMap<String, OutgoingMessage>> = Mutex::new(HashMap::new());
+}
+
+fn start_worker() -> Sender<OutgoingMessage> {
+    let (tx, rx) = unbounded::<OutgoingMessage>();
+
+    thread::Builder::new()
+        .name("Message sender".into())
+        .spawn(move || {
+            for msg in rx {
+                send_with_retry(msg);
+            }
+        })
+        .expect("Unable to name thread");
+
+    tx
+}
+
+/// Queue a message to be sent, printing a pending local echo until it is confirmed
+pub fn queue_message(buffer: &Buffer, channel_id: ChannelId, content: String) {
+    queue(buffer, channel_id, content, None, None)
+}
+
+/// Queue a reply to a message, like `queue_message`
+pub fn queue_reply(buffer: &Buffer, channel_id: ChannelId, content: String, reply_to: MessageId) {
+    queue(buffer, channel_id, content, None, Some(reply_to))
+}
+
+/// Queue a file to be uploaded, like `queue_message`
+pub fn queue_upload(buffer: &Buffer, channel_id: ChannelId, attachment: Attachment) {
+    queue(buffer, channel_id, String::new(), Some(attachment), None)
+}
+
+fn queue(
+    buffer: &Buffer,
+    channel_id: ChannelId,
+    content: String,
+    attachment: Option<Attachment>,
+    reply_to: Option<MessageId>,
+) {
+    let nonce = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_nanos()
+        .to_string();
+
+    let msg = OutgoingMessage {
+        buffer_name: buffer_name(buffer, channel_id),
+        channel_id,
+        content,
+        attachment,
+        reply_to,
+        nonce,
+    };
+
+    print_local_echo(buffer, &msg);
+    PENDING.lock().insert(msg.nonce.clone(), msg.clone());
+    let _ = QUEUE.lock().send(msg);
+}
+
+/// Queue every failed message in a buffer again
+pub fn resend_failed(buffer: &Buffer, channel_id: ChannelId) -> usize {
+    let buffer_name = buffer_name(buffer, channel_id);
+    let failed: Vec<_> = {
+        let mut failed = FAILED.lock();
+        let nonces: Vec<_> = failed
+            .values()
+            .filter(|msg| msg.buffer_name == buffer_name)
+            .map(|msg| msg.nonce.clone())
+            .collect();
+        nonces
+            .into_iter()
+            .filter_map(|nonce| failed.remove(&nonce))
+            .collect()
+    };
+
+    for msg in &failed {
+        let lines = printing::find_tagged_lines(buffer, &pending_tag(&msg.nonce));
+        printing::rewrite_lines(&lines, &render_local_echo(buffer, msg, false));
+        PENDING.lock().insert(msg.nonce.clone(), msg.clone());
+        let _ = QUEUE.lock().send(msg.clone());
+    }
+    failed.len()
+}
+
+/// Replace the local echo of a message we sent with the confirmed message
+///
+/// Returns false if the message was not sent from this client
+pub fn confirm(weechat: &Weechat, buffer: &Buffer, msg: &Message) -> bool {
+    let nonce = match nonce_string(&msg.nonce) {
+        Some(nonce) => nonce,
+        None => return false,
+    };
+    if PENDING.lock().remove(&nonce).is_none() {
+        return false;
+    }
+    let ctx = match crate::discord::get_ctx() {
+        Some(ctx) => ctx,
+        None => return false,
+    };
+
+    let lines = printing::find_tagged_lines(buffer, &pending_tag(&nonce));
+    if lines.is_empty() {
+        return false;
+    }
+
+    let guild = buffer
+        .get_localvar("guildid")
+        .and_then(|id| id.parse::<u64>().ok().map(GuildId));
+
+    message_cache::insert(msg);
+    let (prefix, content) = printing::render_msg(&ctx.cache, weechat, msg, guild);
+    let tags = printing::msg_tags(weechat, &ctx.cache, msg, guild, false).join(",");
+    printing::rewrite_lines(&lines, &content);
+    for line in &lines {
+        line.update_var("prefix", prefix.clone());
+        line.update_var("tags_array", tags.clone());
+    }
+    printing::track_newest_message(buffer, msg.id);
+
+    true
+}
+
+fn send_with_retry(msg: OutgoingMessage) {
+    let ctx = match crate::discord::get_ctx() {
+        Some(ctx) => ctx,
+        None => return mark_failed(msg, "not connected".to_owned()),
+    };
+
+    let mut error = None;
+    for attempt in 1..=MAX_SEND_ATTEMPTS {
+        let result = match &msg.attachment {
+            None => msg.channel_id.send_message(&ctx.http, |m| {
+                m.0.insert("nonce", serde_json::json!(msg.nonce));
+                if let Some(reply_to) = msg.reply_to {
+                    m.0.insert(
+                        "message_reference",
+                        serde_json::json!({ "message_id": reply_to.0 }),
+                    );
+                }
+                m.content(&msg.content)
+            }),
+            Some(Attachment::File(path)) => {
+                msg.channel_id
+                    .send_files(&ctx.http, vec![path.as_str()], |m| {
+                        m.0.insert("nonce", serde_json::json!(msg.nonce));
+                        m
+                    })
+            }
+            Some(Attachment::Text { content, filename }) => msg.channel_id.send_files(
+                &ctx.http,
+                vec![(content.as_bytes(), filename.as_str())],
+                |m| {
+                    m.0.insert("nonce", serde_json::json!(msg.nonce));
+                    m
+                },
+            ),
+        };
+
+        match result {
+            Ok(sent) => {
+                // The gateway usually echoes the message first, but we don't have to wait for it
+                let buffer_name = msg.buffer_name.clone();
+                on_main(move |weechat| {
+                    if let Some(buffer) = weechat.buffer_search("weecord", &buffer_name) {
+                        confirm(weechat, &buffer, &sent);
+                    }
+                });
+                return;
+            }
+            // Sending again is only safe when Discord can't have posted the message
+            Err(e) if is_retryable(&e) => {
+                error = Some(e);
+                if attempt < MAX_SEND_ATTEMPTS {
+                    thread::sleep(Duration::from_secs(2u64.pow(attempt)))
+                }
+            }
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        }
+    }
+
+    // The request may have gone through, like when a sent message's response couldn't be
+    // parsed, so the echo from the gateway decides whether it was sent
+    if let Some(e) = error.as_ref().filter(|e| !is_rejected(e)) {
+        let error = e.to_string();
+        thread::spawn(move || {
+            thread::sleep(ECHO_TIMEOUT);
+            if PENDING.lock().contains_key(&msg.nonce) {
+                mark_failed(msg, format!("no confirmation from Discord ({})", error));
+            }
+        });
+        return;
+    }
+
+    let error = match error {
+        Some(serenity::Error::Model(serenity::model::ModelError::MessageTooLong(_))) => {
+            "file too large".to_owned()
+        }
+        Some(e) => e.to_string(),
+        None => "unknown error".to_owned(),
+    };
+    mark_failed(msg, error);
+}
+
+/// Whether a send failed in a way that means Discord didn't post the message, and that
+/// sending it again may fix
+fn is_retryable(error: &serenity::Error) -> bool {
+    match error {
+        serenity::Error::Http(e) => match &**e {
+            HttpError::UnsuccessfulRequest(response) => {
+                let status = response.status_code.as_u16();
+                status == 429 || status >= 500
+            }
+            HttpError::Request(e) => is_connection_error(e),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether a send failed before the message could have been posted
+fn is_rejected(error: &serenity::Error) -> bool {
+    match error {
+        serenity::Error::Model(_) | serenity::Error::Io(_) => true,
+        serenity::Error::Http(e) => match &**e {
+            HttpError::UnsuccessfulRequest(_) => true,
+            HttpError::Request(e) => is_connection_error(e),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether a request failed to connect, before anything was sent
+fn is_connection_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<std::io::Error>() {
+            return e.kind() == ErrorKind::ConnectionRefused
+                || e.kind() == ErrorKind::AddrNotAvailable;
+        }
+        source = e.source();
+    }
+    false
+}
+
+fn mark_failed(msg: OutgoingMessage, error: String) {
+    PENDING.lock().remove(&msg.nonce);
+    FAILED.lock().insert(msg.nonce.clone(), msg.clone());
+
+    on_main(move |weechat| {
+        if let Some(buffer) = weechat.buffer_search("weecord", &msg.buffer_name) {
+            let lines = printing::find_tagged_lines(&buffer, &pending_tag(&msg.nonce));
+            printing::rewrite_lines(&lines, &render_local_echo(&buffer, &msg, true));
+            buffer.print(&format!("[discord] Unable to send message: {}", error));
+        }
+    });
+}
+
+fn print_local_echo(buffer: &Buffer, msg: &OutgoingMessage) {
+    let ctx = match crate::discord::get_ctx() {
+        Some(ctx) => ctx,
+        None => return,
+    };
+    let nick = buffer
+        .get_localvar("nick")
+        .map(|nick| nick.trim_start_matches('@').to_owned())
+        .unwrap_or_else(|| ctx.cache.read().user.name.clone());
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as i64;
+    // The echo is what gets logged, since lines can't be logged again once they are confirmed
+    let tags = format!("notify_none,self_msg,{}", pending_tag(&msg.nonce));
+    buffer.print_tags_dated(
+        timestamp,
+        &tags,
+        &format!("{}\t{}", nick, render_local_echo(buffer, msg, false)),
+    );
+}
+
+fn render_local_echo(buffer: &Buffer, msg: &OutgoingMessage, failed: bool) -> String {
+    let weechat = buffer.get_weechat();
+    // Messages can quote others' spoilers, so they are hidden here too
+    let opts = FormatOptions {
+        guild: buffer
+            .get_localvar("guildid")
+            .and_then(|id| id.parse::<u64>().ok().map(GuildId)),
+        ..FormatOptions::default()
+    };
+    let content = formatting::discord_to_plain(&weechat, &msg.echo(), opts);
+    let mut echo = content
+        .lines()
+        .map(|line| format!("{}{}{}", weechat.color("8"), line, weechat.color("reset")))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if failed {
+        echo.push_str(&format!(
+            " {}(failed to send, use /discord resend to retry){}",
+            weechat.color("red"),
+            weechat.color("reset")
+        ));
+    }
+    echo
+}
+
+fn buffer_name(buffer: &Buffer, channel_id: ChannelId) -> String {
+    let guild_id = buffer
+        .get_localvar("guildid")
+        .and_then(|id| id.parse::<u64>().ok().map(GuildId));
+    utils::buffer_id_for_channel(guild_id, channel_id)
+}
+
+fn pending_tag(nonce: &str) -> String {
+    format!("discord_pending_{}", nonce)
+}
+
+fn nonce_string(nonce: &serde_json::Value) -> Option<String> {
+    use serde_json::Value;
+    match nonce {
+        Value::String(nonce) => Some(nonce.clone()),
+        Value::Number(nonce) => Some(nonce.to_string()),
+        _ => None,
+    }
+}
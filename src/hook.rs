@@ -1,7 +1,9 @@
 use crate::utils::ChannelExt;
 use crate::{discord, on_main, plugin_print, utils};
 use crossbeam_channel::unbounded;
+use lazy_static::lazy_static;
 use serenity::{model::prelude::*, prelude::*};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
@@ -9,6 +11,30 @@ use weechat::{Buffer, CompletionPosition, ConfigOption, ReturnCode, Weechat};
 
 static mut LAST_TYPING_TIMESTAMP: u64 = 0;
 
+/// Discord rejects messages longer than this many characters
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+/// How long to wait for more input before sending, so pasted lines are sent as one message
+const PASTE_WINDOW: Duration = Duration::from_millis(50);
+
+#[derive(Default)]
+struct PendingInput {
+    lines: Vec<String>,
+    /// Whether lines are being collected until `/discord multiline end`
+    multiline: bool,
+}
+
+/// The command weecord.main.edit_cancel_key is bound to while editing
+const CANCEL_EDIT_COMMAND: &str = "/discord edit cancel";
+
+lazy_static! {
+    static ref PENDING_INPUT: Mutex<HashMap<String, PendingInput>> = Mutex::new(HashMap::new());
+    /// Messages being edited with `/discord edit`, by the buffer they are being edited in
+    static ref PENDING_EDITS: Mutex<HashMap<String, MessageId>> = Mutex::new(HashMap::new());
+    /// The key bound to cancel edits, while any message is being edited
+    static ref CANCEL_EDIT_KEY: Mutex<Option<String>> = Mutex::new(None);
+}
+
 pub struct HookHandles {
     _buffer_switch_handle: weechat::SignalHook<()>,
     _buffer_typing_handle: weechat::SignalHook<()>,
@@ -21,6 +47,24 @@ pub struct HookHandles {
     _dm_completion_handle: weechat::CompletionHook<()>,
     _nick_completion_handle: weechat::CompletionHook<()>,
     _role_completion_handle: weechat::CompletionHook<()>,
+    _emoji_completion_handle: weechat::CompletionHook<()>,
+    _focus_handle: Option<FocusHook>,
+}
+
+/// A `hook_focus`, which the weechat crate doesn't wrap, unhooked when dropped
+struct FocusHook {
+    plugin: *mut weechat_sys::t_weechat_plugin,
+    hook: *mut weechat_sys::t_hook,
+}
+
+impl Drop for FocusHook {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(unhook) = (*self.plugin).unhook {
+                unhook(self.hook);
+            }
+        }
+    }
 }
 
 pub fn init(weechat: &Weechat) -> HookHandles {
@@ -97,6 +141,15 @@ pub fn init(weechat: &Weechat) -> HookHandles {
         None,
     );
 
+    let _emoji_completion_handle = weechat.hook_completion(
+        "weecord_emoji_completion",
+        "Completion for emoji shortcodes, including the guild's custom emoji",
+        |_, ref buffer, _, completions| handle_emoji_completion(buffer, completions),
+        None,
+    );
+
+    let _focus_handle = hook_chat_focus(weechat);
+
     HookHandles {
         _buffer_switch_handle,
         _buffer_typing_handle,
@@ -109,9 +162,76 @@ pub fn init(weechat: &Weechat) -> HookHandles {
         _dm_completion_handle,
         _nick_completion_handle,
         _role_completion_handle,
+        _emoji_completion_handle,
+        _focus_handle,
+    }
+}
+
+/// Tell key and mouse bindings which message is under the cursor or mouse
+fn hook_chat_focus(weechat: &Weechat) -> Option<FocusHook> {
+    let plugin = weechat.as_ptr();
+    let area = std::ffi::CString::new("chat").ok()?;
+    let hook = unsafe {
+        ((*plugin).hook_focus?)(
+            plugin,
+            area.as_ptr(),
+            Some(handle_chat_focus),
+            std::ptr::null(),
+            std::ptr::null_mut(),
+        )
+    };
+    if hook.is_null() {
+        None
+    } else {
+        Some(FocusHook { plugin, hook })
     }
 }
 
+/// Add `_discord_msgid`, `_discord_author` and `_discord_channel` to the focus info of Discord
+/// messages, taken from the tags of the focused line
+unsafe extern "C" fn handle_chat_focus(
+    _pointer: *const std::os::raw::c_void,
+    _data: *mut std::os::raw::c_void,
+    info: *mut weechat_sys::t_hashtable,
+) -> *mut weechat_sys::t_hashtable {
+    use std::ffi::{CStr, CString};
+
+    let plugin = match crate::__PLUGIN.as_ref() {
+        Some(weecord) => weecord.weechat.as_ptr(),
+        None => return info,
+    };
+    let (hashtable_get, hashtable_set) = match ((*plugin).hashtable_get, (*plugin).hashtable_set) {
+        (Some(get), Some(set)) => (get, set),
+        _ => return info,
+    };
+
+    let tags_key = CString::new("_chat_line_tags").unwrap();
+    let tags = hashtable_get(info, tags_key.as_ptr() as *const _);
+    if tags.is_null() {
+        return info;
+    }
+    let tags = CStr::from_ptr(tags as *const _)
+        .to_string_lossy()
+        .into_owned();
+
+    for (prefix, key) in &[
+        ("discord_msgid_", "_discord_msgid"),
+        ("discord_author_", "_discord_author"),
+        ("discord_channel_", "_discord_channel"),
+    ] {
+        let value = tags
+            .split(',')
+            .find(|tag| tag.starts_with(prefix))
+            .map(|tag| &tag[prefix.len()..]);
+        if let (Some(value), Ok(key)) = (value, CString::new(*key)) {
+            if let Ok(value) = CString::new(value) {
+                hashtable_set(info, key.as_ptr() as *const _, value.as_ptr() as *const _);
+            }
+        }
+    }
+    info
+}
+
 pub fn buffer_input(buffer: Buffer, text: &str) {
     let channel = buffer
         .get_localvar("channelid")
@@ -124,71 +244,310 @@ pub fn buffer_input(buffer: Buffer, text: &str) {
         .map(GuildId);
 
     if let Some(channel) = channel {
-        let ctx = match crate::discord::get_ctx() {
-            Some(ctx) => ctx,
-            _ => return,
+        if crate::discord::get_ctx().is_none() {
+            return;
+        }
+
+        let buffer_name = utils::buffer_id_for_channel(guild, channel);
+        let collecting = {
+            let mut pending = PENDING_INPUT.lock();
+            match pending.get_mut(&buffer_name) {
+                Some(input) if input.multiline => {
+                    input.lines.push(text.to_owned());
+                    let weechat = buffer.get_weechat();
+                    buffer.print(&format!("\t{}{}", weechat.color("8"), text));
+                    return;
+                }
+                Some(input) => !input.lines.is_empty(),
+                None => false,
+            }
         };
 
-        if let Some(edit) = parsing::parse_line_edit(text) {
-            let weechat = buffer.get_weechat();
-            match edit {
-                parsing::LineEdit::Delete { line } => {
-                    if let Err(e) = crate::utils::get_users_nth_message(&ctx, channel, line)
-                        .map(|msg| channel.delete_message(&ctx.http, msg.id))
-                    {
-                        buffer.print(&format!(
-                            "[discord] An error occurred deleting a message: {}",
-                            e
-                        ));
-                    } else {
-                        buffer.print(&format!(
-                            "{}\tMessage ({}) deleted",
-                            weechat.get_prefix("network"),
-                            line,
-                        ))
-                    }
+        if !collecting {
+            if let Some(message_id) = cancel_edit(&buffer) {
+                if !text.trim().is_empty() {
+                    edit_message(buffer_name, channel, guild, message_id, text.to_owned());
                 }
-                parsing::LineEdit::Sub {
-                    line,
-                    old,
-                    new,
-                    options,
-                } => {
-                    // TODO: Clean this up, (try block)?
-                    if let Err(e) =
-                        crate::utils::get_users_nth_message(&ctx, channel, line).map(|mut msg| {
-                            let orig = msg.content.clone();
-                            msg.edit(ctx, |e| {
-                                if options.map(|o| o.contains('g')).unwrap_or_default() {
-                                    e.content(orig.replace(old, new))
-                                } else {
-                                    e.content(orig.replacen(old, new, 1))
-                                }
-                            })
-                        })
-                    {
-                        buffer.print(&format!(
-                            "[discord] An error occurred editing a message: {}",
-                            e
-                        ));
-                    } else {
-                        buffer.print(&format!(
-                            "{}\t{}s/{}/{}/{}",
-                            weechat.get_prefix("network"),
-                            line,
-                            old,
-                            new,
-                            options.unwrap_or_default()
-                        ))
+                return;
+            }
+        }
+
+        // Pasted lines are never edits
+        if let (false, Some(edit)) = (collecting, parsing::parse_line_edit(text)) {
+            apply_line_edit(buffer_name, channel, guild, edit);
+            return;
+        }
+        // Pasted lines all arrive at once, so wait a moment and send them as a single message
+        let first_line = {
+            let mut pending = PENDING_INPUT.lock();
+            let input = pending.entry(buffer_name.clone()).or_default();
+            input.lines.push(text.to_owned());
+            input.lines.len() == 1
+        };
+        if first_line {
+            thread::spawn(move || {
+                thread::sleep(PASTE_WINDOW);
+                on_main(move |weechat| {
+                    let lines = match PENDING_INPUT.lock().remove(&buffer_name) {
+                        Some(input) => input.lines,
+                        None => return,
+                    };
+                    if let Some(buffer) = weechat.buffer_search("weecord", &buffer_name) {
+                        send_message(&buffer, &lines.join("\n"));
+                    }
+                });
+            });
+        }
+    }
+}
+
+/// Apply a line edit in the background, since finding the nth message can take several requests
+fn apply_line_edit(
+    buffer_name: String,
+    channel: ChannelId,
+    guild: Option<GuildId>,
+    edit: parsing::LineEdit,
+) {
+    let task: EditTask = match edit {
+        parsing::LineEdit::Delete { target } => Box::new(move |ctx| {
+            utils::get_users_message(ctx, channel, target)
+                .and_then(|msg| channel.delete_message(&ctx.http, msg.id))
+                .map(|_| Some(format!("Message ({}) deleted", target)))
+                .map_err(|e| format!("An error occurred deleting a message: {}", e))
+        }),
+        parsing::LineEdit::React {
+            target,
+            emoji,
+            remove,
+        } => {
+            let emoji = emoji.to_owned();
+            Box::new(move |ctx| {
+                utils::react_to_message(ctx, channel, guild, target, &emoji, remove)
+                    .map(|_| None)
+                    .map_err(|e| format!("An error occurred reacting to a message: {}", e))
+            })
+        }
+        parsing::LineEdit::Sub {
+            target,
+            old,
+            new,
+            options,
+            delimiter,
+        } => {
+            let options = options.map(str::to_owned);
+            Box::new(move |ctx| {
+                let mut msg = utils::get_users_message(ctx, channel, target)
+                    .map_err(|e| format!("An error occurred editing a message: {}", e))?;
+                // An escaped delimiter is meant literally, even when it is special in a regex
+                let pattern = old.replace(delimiter, &regex::escape(&delimiter.to_string()));
+                let content =
+                    utils::apply_substitution(&msg.content, &pattern, &new, options.as_deref())
+                        .map_err(|e| format!("An error occurred editing a message: {}", e))?;
+                msg.edit(ctx, |e| e.content(content))
+                    .map_err(|e| format!("An error occurred editing a message: {}", e))?;
+
+                let escaped = format!("\\{}", delimiter);
+                let escape = |text: &str| text.replace(delimiter, &escaped);
+                Ok(Some(format!(
+                    "{}s{d}{}{d}{}{d}{}",
+                    target,
+                    escape(&old),
+                    escape(&new),
+                    options.unwrap_or_default(),
+                    d = delimiter,
+                )))
+            })
+        }
+    };
+    run_edit_task(buffer_name, task);
+}
+
+/// Work on a message done in the background, returning what to report in its buffer
+type EditTask = Box<dyn FnOnce(&Context) -> Result<Option<String>, String> + Send>;
+
+/// Run a task off the main thread, as it makes requests, and print its result in the buffer
+fn run_edit_task(buffer_name: String, task: EditTask) {
+    thread::spawn(move || {
+        let ctx = match discord::get_ctx() {
+            Some(ctx) => ctx,
+            None => return,
+        };
+        let result = task(ctx);
+        on_main(move |weechat| {
+            if let Some(buffer) = weechat.buffer_search("weecord", &buffer_name) {
+                match result {
+                    Ok(Some(message)) => {
+                        buffer.print(&format!("{}\t{}", weechat.get_prefix("network"), message))
                     }
+                    Ok(None) => {}
+                    Err(e) => buffer.print(&format!("[discord] {}", e)),
                 }
             }
-            return;
+        });
+    });
+}
+
+/// Start collecting input in a buffer to be sent as a single message
+pub fn start_multiline(buffer: &Buffer) -> bool {
+    let buffer_name = match input_buffer_name(buffer) {
+        Some(name) => name,
+        None => return false,
+    };
+    PENDING_INPUT
+        .lock()
+        .entry(buffer_name)
+        .or_default()
+        .multiline = true;
+    true
+}
+
+/// Send all input collected since `start_multiline` as a single message
+pub fn end_multiline(buffer: &Buffer) {
+    let input = input_buffer_name(buffer).and_then(|name| PENDING_INPUT.lock().remove(&name));
+    if let Some(input) = input {
+        if !input.lines.is_empty() {
+            send_message(buffer, &input.lines.join("\n"));
+        }
+    }
+}
+
+/// Load a message into the input bar, so that the next input edits it instead of being sent
+pub fn start_edit(buffer: &Buffer, msg: &Message) -> bool {
+    let buffer_name = match input_buffer_name(buffer) {
+        Some(name) => name,
+        None => return false,
+    };
+
+    PENDING_EDITS.lock().insert(buffer_name, msg.id);
+    let weechat = buffer.get_weechat();
+    update_cancel_edit_key(&weechat);
+    weechat.update_bar_item("discord_edit");
+    buffer.set_input(&msg.content);
+    true
+}
+
+/// Stop editing a message, returning the id of the message that was being edited
+pub fn cancel_edit(buffer: &Buffer) -> Option<MessageId> {
+    let message_id =
+        input_buffer_name(buffer).and_then(|name| PENDING_EDITS.lock().remove(&name))?;
+    let weechat = buffer.get_weechat();
+    update_cancel_edit_key(&weechat);
+    weechat.update_bar_item("discord_edit");
+    Some(message_id)
+}
+
+/// Whether a message is being edited in a buffer
+pub fn is_editing(buffer: &Buffer) -> bool {
+    input_buffer_name(buffer).map_or(false, |name| PENDING_EDITS.lock().contains_key(&name))
+}
+
+/// Bind weecord.main.edit_cancel_key while any message is being edited, and unbind it after
+fn update_cancel_edit_key(weechat: &Weechat) {
+    let editing = !PENDING_EDITS.lock().is_empty();
+    let mut bound = CANCEL_EDIT_KEY.lock();
+    if editing && bound.is_none() {
+        let key = crate::upgrade_plugin(weechat)
+            .config
+            .edit_cancel_key
+            .value()
+            .into_owned();
+        if !key.is_empty() && utils::bind_key(weechat, "default", &key, CANCEL_EDIT_COMMAND) {
+            *bound = Some(key);
+        }
+    } else if !editing {
+        if let Some(key) = bound.take() {
+            utils::unbind_key(weechat, "default", &key, CANCEL_EDIT_COMMAND);
+        }
+    }
+}
+
+/// Unbind the key that cancels edits, for when weecord is unloaded mid-edit
+pub fn unbind_cancel_edit_key(weechat: &Weechat) {
+    if let Some(key) = CANCEL_EDIT_KEY.lock().take() {
+        utils::unbind_key(weechat, "default", &key, CANCEL_EDIT_COMMAND);
+    }
+}
+
+fn edit_message(
+    buffer_name: String,
+    channel: ChannelId,
+    guild: Option<GuildId>,
+    message_id: MessageId,
+    text: String,
+) {
+    run_edit_task(
+        buffer_name,
+        Box::new(move |ctx| {
+            let text = utils::create_mentions(&ctx.cache, guild, &text);
+            channel
+                .edit_message(&ctx.http, message_id, |e| e.content(text))
+                .map(|_| None)
+                .map_err(|e| format!("An error occurred editing a message: {}", e))
+        }),
+    );
+}
+
+fn input_buffer_name(buffer: &Buffer) -> Option<String> {
+    let channel = buffer
+        .get_localvar("channelid")
+        .and_then(|id| id.parse().ok())
+        .map(ChannelId)?;
+    let guild = buffer
+        .get_localvar("guildid")
+        .and_then(|id| id.parse().ok())
+        .map(GuildId);
+
+    Some(utils::buffer_id_for_channel(guild, channel))
+}
+
+pub(crate) fn send_message(buffer: &Buffer, text: &str) {
+    let channel = match buffer
+        .get_localvar("channelid")
+        .and_then(|id| id.parse().ok())
+        .map(ChannelId)
+    {
+        Some(channel) => channel,
+        None => return,
+    };
+    let guild = buffer
+        .get_localvar("guildid")
+        .and_then(|id| id.parse().ok())
+        .map(GuildId);
+    let ctx = match crate::discord::get_ctx() {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+
+    send_discord_text(
+        buffer,
+        channel,
+        utils::create_mentions(&ctx.cache, guild, text),
+    );
+}
+
+/// Send text that is already in Discord's syntax, splitting or attaching it if it is too long
+pub(crate) fn send_discord_text(buffer: &Buffer, channel: ChannelId, text: String) {
+    if text.chars().count() <= MAX_MESSAGE_LENGTH {
+        discord::send_queue::queue_message(&buffer, channel, text);
+        return;
+    }
+
+    let weechat = buffer.get_weechat();
+    let overflow = crate::upgrade_plugin(&weechat)
+        .config
+        .message_overflow
+        .value()
+        .into_owned();
+    if overflow == "attach" {
+        let attachment = discord::send_queue::Attachment::Text {
+            content: text,
+            filename: "message.txt".to_owned(),
+        };
+        discord::send_queue::queue_upload(&buffer, channel, attachment);
+    } else {
+        for chunk in parsing::split_message(&text, MAX_MESSAGE_LENGTH) {
+            discord::send_queue::queue_message(&buffer, channel, chunk);
         }
-        let text = utils::create_mentions(&ctx.cache, guild, text);
-        channel
-            .say(ctx, text)
-            .unwrap_or_else(|_| panic!("Unable to send message to {}", channel.0));
     }
 }
 
@@ -233,9 +592,34 @@ fn handle_buffer_switch(data: weechat::SignalHookValue) -> ReturnCode {
     ReturnCode::Ok
 }
 
+/// Clearing the input cancels an edit, but pressing enter clears the input before it is handled,
+/// so wait until after that to check
+fn cancel_edit_if_input_empty(buffer: &Buffer) {
+    let buffer_name = match input_buffer_name(buffer) {
+        Some(name) => name,
+        None => return,
+    };
+    if !PENDING_EDITS.lock().contains_key(&buffer_name) {
+        return;
+    }
+
+    thread::spawn(move || {
+        on_main(move |weechat| {
+            if let Some(buffer) = weechat.buffer_search("weecord", &buffer_name) {
+                if buffer.input().is_empty() {
+                    cancel_edit(&buffer);
+                }
+            }
+        })
+    });
+}
+
 fn handle_buffer_typing(weechat: &Weechat, data: weechat::SignalHookValue) -> ReturnCode {
     if let weechat::SignalHookValue::Pointer(buffer_ptr) = data {
         let buffer = unsafe { crate::utils::buffer_from_ptr(buffer_ptr) };
+        if buffer.input().is_empty() {
+            cancel_edit_if_input_empty(&buffer);
+        }
         if let Some(chnanel_id) = buffer.get_localvar("channelid") {
             if crate::upgrade_plugin(weechat)
                 .config
@@ -389,6 +773,29 @@ fn handle_role_completion(buffer: &Buffer, completion: weechat::Completion) -> R
     ReturnCode::Ok
 }
 
+fn handle_emoji_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
+    let ctx = match discord::get_ctx() {
+        Some(s) => s,
+        None => return ReturnCode::Ok,
+    };
+
+    let guild = buffer
+        .get_localvar("guildid")
+        .and_then(|id| id.parse().ok())
+        .map(GuildId);
+
+    if let Some(guild) = guild.and_then(|guild| guild.to_guild_cached(&ctx.cache)) {
+        for emoji in guild.read().emojis.values() {
+            completion.add(&format!(":{}:", emoji.name));
+        }
+    }
+    for shortcode in parsing::emoji::shortcodes() {
+        completion.add(&format!(":{}:", shortcode));
+    }
+
+    ReturnCode::Ok
+}
+
 // TODO: Make this faster
 // TODO: Handle command options
 pub fn handle_query(command: &str) -> ReturnCode {
@@ -4,6 +4,7 @@ mod command;
 mod config;
 mod discord;
 mod hook;
+mod message_cache;
 mod printing;
 mod sync;
 mod utils;
@@ -36,6 +37,7 @@ impl WeechatPlugin for Discord {
 
         let autostart = config.autostart.value();
         let irc_mode = config.irc_mode.value();
+        command::update_cursor_keys(&weechat, config.cursor_keys.value());
         let token = config.token.value().into_owned();
         let token = if token.starts_with("${sec.data") {
             weechat.eval_string_expression(&token).map(Cow::into_owned)
@@ -71,6 +73,7 @@ impl WeechatPlugin for Discord {
 
 impl Drop for Discord {
     fn drop(&mut self) {
+        hook::unbind_cancel_edit_key(&self.weechat);
         // TODO: Why is the config file not saved on quit?
         self.config.config.write()
     }
@@ -0,0 +1,290 @@
+use chrono::{DateTime, FixedOffset};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde_json::Value;
+use serenity::{
+    http::{request::RequestBuilder, routing::RouteInfo, Http},
+    model::prelude::*,
+    prelude::*,
+};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::thread;
+
+/// Most messages kept per channel, older ones are forgotten first
+const MAX_MESSAGES_PER_CHANNEL: usize = 1000;
+/// Most messages remembered as unavailable
+const MAX_UNAVAILABLE: usize = 100;
+/// Most type numbers remembered for messages of types serenity doesn't know, oldest are
+/// forgotten first
+const MAX_UNKNOWN_KINDS: usize = 1000;
+/// Most previous versions kept of each message, older ones are forgotten first
+const MAX_REVISIONS_PER_MESSAGE: usize = 20;
+
+/// A previous version of an edited message
+#[derive(Clone)]
+pub struct Revision {
+    pub content: String,
+    /// When this version was sent or edited
+    pub timestamp: DateTime<FixedOffset>,
+}
+
+lazy_static! {
+    /// Messages seen by weecord, ordered by id (and so by creation time) within each channel
+    static ref MESSAGES: Mutex<HashMap<ChannelId, BTreeMap<MessageId, Message>>> =
+        Mutex::new(HashMap::new());
+    /// Previous versions of edited messages, oldest first
+    static ref REVISIONS: Mutex<HashMap<MessageId, Vec<Revision>>> = Mutex::new(HashMap::new());
+    /// Messages that have been deleted but are kept around to be displayed
+    static ref DELETED: Mutex<HashSet<MessageId>> = Mutex::new(HashSet::new());
+    /// Messages being fetched in the background, with what to do once they have been
+    static ref FETCHING: Mutex<HashMap<MessageId, Vec<Box<dyn FnOnce() + Send>>>> =
+        Mutex::new(HashMap::new());
+    /// Messages that could not be fetched, most recent last
+    static ref UNAVAILABLE: Mutex<VecDeque<(MessageId, Unavailable)>> =
+        Mutex::new(VecDeque::new());
+    /// Type numbers of fetched messages whose type serenity doesn't know, which it was given as
+    /// regular messages
+    static ref UNKNOWN_KINDS: Mutex<BTreeMap<MessageId, u8>> = Mutex::new(BTreeMap::new());
+}
+
+/// Why a message could not be fetched
+#[derive(Clone, Copy, PartialEq)]
+pub enum Unavailable {
+    Deleted,
+    Failed,
+}
+
+/// The result of `get_or_fetch_later`
+pub enum Lookup {
+    Found(Message),
+    Unavailable(Unavailable),
+    /// The message is being fetched
+    Pending,
+}
+
+/// Cache a message, remembering the previous version if its content has changed
+pub fn insert(msg: &Message) {
+    let (old, evicted) = {
+        let mut messages = MESSAGES.lock();
+        let channel = messages.entry(msg.channel_id).or_default();
+        let old = channel.insert(msg.id, msg.clone());
+        let evicted = if channel.len() > MAX_MESSAGES_PER_CHANNEL {
+            let oldest = *channel.keys().next().expect("the channel has messages");
+            channel.remove(&oldest);
+            Some(oldest)
+        } else {
+            None
+        };
+        (old, evicted)
+    };
+    if let Some(evicted) = evicted {
+        REVISIONS.lock().remove(&evicted);
+        DELETED.lock().remove(&evicted);
+    }
+
+    if let Some(old) = old {
+        if old.content != msg.content {
+            let mut revisions = REVISIONS.lock();
+            let revisions = revisions.entry(msg.id).or_default();
+            revisions.push(Revision {
+                timestamp: old.edited_timestamp.unwrap_or(old.timestamp),
+                content: old.content,
+            });
+            if revisions.len() > MAX_REVISIONS_PER_MESSAGE {
+                revisions.remove(0);
+            }
+        }
+    }
+}
+
+/// Previous versions of a message, oldest first
+pub fn revisions(message_id: MessageId) -> Vec<Revision> {
+    REVISIONS
+        .lock()
+        .get(&message_id)
+        .cloned()
+        .unwrap_or_default()
+}
+
+pub fn mark_deleted(message_id: MessageId) {
+    DELETED.lock().insert(message_id);
+}
+
+pub fn is_deleted(message_id: MessageId) -> bool {
+    DELETED.lock().contains(&message_id)
+}
+
+pub fn get(channel_id: ChannelId, message_id: MessageId) -> Option<Message> {
+    MESSAGES
+        .lock()
+        .get(&channel_id)
+        .and_then(|msgs| msgs.get(&message_id))
+        .cloned()
+}
+
+pub fn remove(channel_id: ChannelId, message_id: MessageId) -> Option<Message> {
+    MESSAGES
+        .lock()
+        .get_mut(&channel_id)
+        .and_then(|msgs| msgs.remove(&message_id))
+}
+
+/// Get a message from the cache, fetching it from Discord in the background if it is missing
+///
+/// `fetched` is called from the background thread once the fetch has finished, successfully or not
+pub fn get_or_fetch_later(
+    channel_id: ChannelId,
+    message_id: MessageId,
+    fetched: impl FnOnce() + Send + 'static,
+) -> Lookup {
+    if let Some(msg) = get(channel_id, message_id) {
+        return Lookup::Found(msg);
+    }
+    if let Some((_, reason)) = UNAVAILABLE.lock().iter().find(|(id, _)| *id == message_id) {
+        return Lookup::Unavailable(*reason);
+    }
+
+    let mut fetching = FETCHING.lock();
+    if let Some(callbacks) = fetching.get_mut(&message_id) {
+        callbacks.push(Box::new(fetched));
+        return Lookup::Pending;
+    }
+    fetching.insert(message_id, vec![Box::new(fetched)]);
+
+    thread::spawn(move || {
+        let result = match crate::discord::get_ctx() {
+            Some(ctx) => fetch_message(&ctx.http, channel_id, message_id),
+            None => Err(serenity::Error::Other("Not connected")),
+        };
+        match result {
+            Ok(msg) => insert(&msg),
+            Err(e) => {
+                let reason = if crate::utils::is_not_found(&e) {
+                    Unavailable::Deleted
+                } else {
+                    Unavailable::Failed
+                };
+                mark_unavailable(message_id, reason);
+            }
+        }
+
+        let callbacks = FETCHING.lock().remove(&message_id).unwrap_or_default();
+        for callback in callbacks {
+            callback();
+        }
+    });
+    Lookup::Pending
+}
+
+fn mark_unavailable(message_id: MessageId, reason: Unavailable) {
+    let mut unavailable = UNAVAILABLE.lock();
+    unavailable.retain(|(id, _)| *id != message_id);
+    if unavailable.len() >= MAX_UNAVAILABLE {
+        unavailable.pop_front();
+    }
+    unavailable.push_back((message_id, reason));
+}
+
+/// Get a message from the cache, fetching it from Discord (and caching it) if it is missing
+pub fn get_or_fetch(
+    ctx: &Context,
+    channel_id: ChannelId,
+    message_id: MessageId,
+) -> Option<Message> {
+    if let Some(msg) = get(channel_id, message_id) {
+        return Some(msg);
+    }
+
+    let msg = fetch_message(&ctx.http, channel_id, message_id).ok()?;
+    insert(&msg);
+    Some(msg)
+}
+
+/// The type number of a message, including types serenity doesn't know
+pub fn kind(msg: &Message) -> u8 {
+    UNKNOWN_KINDS
+        .lock()
+        .get(&msg.id)
+        .copied()
+        .unwrap_or(msg.kind as u8)
+}
+
+/// Fetch a message from Discord, without failing on types serenity doesn't know
+pub fn fetch_message(
+    http: &Http,
+    channel_id: ChannelId,
+    message_id: MessageId,
+) -> serenity::Result<Message> {
+    let value: Value = http.fire(
+        RequestBuilder::new(RouteInfo::GetMessage {
+            channel_id: channel_id.0,
+            message_id: message_id.0,
+        })
+        .build(),
+    )?;
+    parse_message(value)
+}
+
+/// Fetch a page of messages from Discord, newest first, without failing on types serenity
+/// doesn't know
+///
+/// `query` is passed on as is, like `?limit=50&before=123`
+pub fn fetch_messages(
+    http: &Http,
+    channel_id: ChannelId,
+    query: &str,
+) -> serenity::Result<Vec<Message>> {
+    let value: Vec<Value> = http.fire(
+        RequestBuilder::new(RouteInfo::GetMessages {
+            channel_id: channel_id.0,
+            query: query.to_owned(),
+        })
+        .build(),
+    )?;
+    value.into_iter().map(parse_message).collect()
+}
+
+/// Deserialize a message, remembering the type number of a type serenity doesn't know and giving
+/// it to serenity as a regular message
+///
+/// Replies come with the message they reply to, which is cached so that their preview doesn't
+/// have to fetch it
+fn parse_message(mut value: Value) -> serenity::Result<Message> {
+    match value.get_mut("referenced_message").map(Value::take) {
+        Some(Value::Null) => {
+            // The reference is kept but the message is gone when it has been deleted
+            let id = value
+                .pointer("/message_reference/message_id")
+                .and_then(Value::as_str)
+                .and_then(|id| id.parse().ok());
+            if let Some(id) = id {
+                mark_unavailable(MessageId(id), Unavailable::Deleted);
+            }
+        }
+        Some(referenced) => {
+            if let Ok(referenced) = parse_message(referenced) {
+                insert(&referenced);
+            }
+        }
+        None => {}
+    }
+
+    if let Some(kind) = value.get("type").and_then(Value::as_u64) {
+        if serde_json::from_value::<MessageType>(kind.into()).is_err() {
+            value["type"] = 0.into();
+            let id = value
+                .get("id")
+                .and_then(Value::as_str)
+                .and_then(|id| id.parse().ok());
+            if let Some(id) = id {
+                let mut kinds = UNKNOWN_KINDS.lock();
+                kinds.insert(MessageId(id), kind as u8);
+                if kinds.len() > MAX_UNKNOWN_KINDS {
+                    let oldest = *kinds.keys().next().expect("kinds are remembered");
+                    kinds.remove(&oldest);
+                }
+            }
+        }
+    }
+    Ok(serde_json::from_value(value)?)
+}
@@ -1,110 +1,531 @@
-use crate::discord::formatting;
+use crate::discord::formatting::{self, FormatOptions};
+use crate::message_cache;
+use crate::utils;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
 use serenity::cache::CacheRwLock;
 use serenity::model::prelude::*;
-use weechat::hdata::HDataPointer;
+use std::collections::{HashSet, VecDeque};
+use weechat::buffer::HotlistPriority;
+use weechat::hdata::{HData, HDataPointer};
 use weechat::{Buffer, HasHData, Weechat};
 
+/// Maximum number of characters of the replied-to message shown above a reply
+const REPLY_PREVIEW_LENGTH: usize = 60;
+/// Discord shows at most this many inline embed fields side by side
+const MAX_INLINE_FIELDS: usize = 3;
+/// Widest a row of inline embed fields can be before they are put on separate lines
+const MAX_EMBED_WIDTH: usize = 80;
+
+/// Tag of the line showing a message's reactions
+const REACTIONS_TAG: &str = "discord_reactions";
+/// Tag of the lines of system messages, like members joining
+const SYSTEM_TAG: &str = "discord_system";
+/// Most messages that can have their spoilers revealed at once
+const MAX_REVEALED_SPOILERS: usize = 100;
+
+lazy_static! {
+    /// Messages whose spoilers have been revealed with /discord reveal, most recent last
+    static ref REVEALED_SPOILERS: Mutex<VecDeque<MessageId>> = Mutex::new(VecDeque::new());
+}
+
+/// Show the spoilers of a message the next time it is rendered
+pub fn reveal_spoilers(message_id: MessageId) {
+    let mut revealed = REVEALED_SPOILERS.lock();
+    if revealed.contains(&message_id) {
+        return;
+    }
+    if revealed.len() >= MAX_REVEALED_SPOILERS {
+        revealed.pop_front();
+    }
+    revealed.push_back(message_id);
+}
+
+/// How a message's content is formatted, wherever it is shown
+pub fn format_options(msg: &Message, guild: Option<GuildId>) -> FormatOptions<'_> {
+    FormatOptions {
+        guild,
+        show_spoilers: REVEALED_SPOILERS.lock().contains(&msg.id),
+        mentions: &msg.mentions,
+    }
+}
+
 pub fn render_msg(
     cache: &CacheRwLock,
     weechat: &Weechat,
     msg: &Message,
     guild: Option<GuildId>,
 ) -> (String, String) {
-    let mut opts = serenity::utils::ContentSafeOptions::new()
-        .clean_here(false)
-        .clean_everyone(false);
-    if let Some(guild) = guild {
-        opts = opts.display_as_member_from(guild);
-    }
-    let mut msg_content = serenity::utils::content_safe(&cache, &msg.content, &opts);
+    let mut msg_content = msg.content.clone();
     if msg.edited_timestamp.is_some() {
         let edited_text =
             weechat.color("8").into_owned() + " (edited)" + &weechat.color("reset").into_owned();
         msg_content.push_str(&edited_text);
     }
 
-    for attachement in &msg.attachments {
-        if !msg_content.is_empty() {
-            msg_content.push('\n');
+    let author = author_display_name(cache, &msg, guild);
+    let opts = format_options(msg, guild);
+
+    if is_regular(msg) {
+        if message_cache::is_deleted(msg.id) {
+            return (
+                author_prefix(weechat, cache, msg, guild),
+                render_deleted(weechat, msg, opts),
+            );
         }
-        msg_content.push_str(&attachement.proxy_url);
-    }
 
-    for embed in &msg.embeds {
-        if !msg_content.is_empty() {
-            msg_content.push('\n');
+        let mut content = formatting::discord_to_weechat(weechat, &msg_content, opts);
+        for attachment in &msg.attachments {
+            if !content.is_empty() {
+                content.push('\n');
+            }
+            content.push_str(&render_attachment(weechat, attachment));
         }
-        if let Some(ref author) = embed.author {
-            msg_content.push_str(&author.name);
-            msg_content.push('\n');
+        for embed in &msg.embeds {
+            if !content.is_empty() {
+                content.push('\n');
+            }
+            content.push_str(&render_embed(weechat, embed, opts));
         }
-        if let Some(ref title) = embed.title {
-            msg_content.push_str(title);
-            msg_content.push('\n');
+        if let Some(preview) = render_reply_preview(cache, weechat, msg, guild) {
+            content = preview + "\n" + &content;
         }
-        if let Some(ref description) = embed.description {
-            msg_content.push_str(description);
-            msg_content.push('\n');
+        return (author_prefix(weechat, cache, msg, guild), content);
+    }
+
+    // The user a group membership message is about, if it isn't the author
+    let target = msg
+        .mentions
+        .first()
+        .filter(|user| user.id != msg.author.id)
+        .map(|user| {
+            guild
+                .and_then(|guild| cache.read().member(guild, user.id))
+                .map(|member| member.display_name().to_string())
+                .unwrap_or_else(|| user.name.clone())
+        });
+
+    let kind = message_cache::kind(msg);
+    let (prefix, body) = match kind {
+        1 => match target {
+            Some(target) => ("join", format!("{} added {} to the group.", author, target)),
+            None => ("join", format!("{} joined the group.", author)),
+        },
+        2 => match target {
+            Some(target) => (
+                "quit",
+                format!("{} removed {} from the group.", author, target),
+            ),
+            None => ("quit", format!("{} left the group.", author)),
+        },
+        3 => ("network", format!("{} started a call.", author)),
+        4 => (
+            "network",
+            format!("{} changed the channel name: {}.", author, msg.content),
+        ),
+        5 => ("network", format!("{} changed the channel icon.", author)),
+        6 => (
+            "network",
+            format!("{} pinned a message to this channel", author),
+        ),
+        7 => ("join", format!("{} joined the server.", author)),
+        8 => (
+            "network",
+            format!("{} boosted this channel with nitro", author),
+        ),
+        9..=11 => (
+            "network",
+            format!("This channel has achieved nitro level {}", kind - 8),
+        ),
+        12 => (
+            "network",
+            format!(
+                "{} followed {}, its updates will show up in this channel",
+                author, msg.content
+            ),
+        ),
+        14 => (
+            "network",
+            "This server has been removed from Server Discovery".to_owned(),
+        ),
+        15 => (
+            "network",
+            "This server is listed in Server Discovery again".to_owned(),
+        ),
+        16 => (
+            "network",
+            "This server has failed Server Discovery activity requirements for 1 week".to_owned(),
+        ),
+        17 => (
+            "network",
+            "This server has failed Server Discovery activity requirements for 3 weeks in a row \
+             and will be removed from Server Discovery"
+                .to_owned(),
+        ),
+        18 => (
+            "network",
+            format!("{} started a thread: {}", author, msg.content),
+        ),
+        21 => (
+            "network",
+            "This thread was started from a message".to_owned(),
+        ),
+        22 => (
+            "network",
+            "Invite your friends to this server to get it going".to_owned(),
+        ),
+        24 => (
+            "network",
+            format!("AutoMod took action on a message from {}", author),
+        ),
+        25 => ("network", format!("{} joined a role subscription", author)),
+        26 => ("network", format!("{} offers premium features", author)),
+        27 => (
+            "network",
+            format!("{} started the stage {}", author, msg.content),
+        ),
+        28 => (
+            "network",
+            format!("{} ended the stage {}", author, msg.content),
+        ),
+        29 => ("network", format!("{} is now a speaker", author)),
+        31 => (
+            "network",
+            format!("{} changed the stage topic: {}", author, msg.content),
+        ),
+        32 => (
+            "network",
+            format!("{} subscribed to an app's premium features", author),
+        ),
+        36 => ("network", format!("{} enabled raid protection", author)),
+        37 => ("network", format!("{} disabled raid protection", author)),
+        38 => ("network", format!("{} reported a raid", author)),
+        39 => (
+            "network",
+            format!("{} reported a false alarm for a raid", author),
+        ),
+        44 => ("network", format!("{} made a purchase", author)),
+        46 => ("network", format!("A poll by {} has closed", author)),
+        _ => ("network", format!("[unsupported message type {}]", kind)),
+    };
+    (weechat.get_prefix(prefix).into_owned(), body)
+}
+
+/// Whether a message has content written by its author, rather than being a system message
+fn is_regular(msg: &Message) -> bool {
+    // Regular messages, replies, and slash and context menu command responses
+    [0, 19, 20, 23].contains(&message_cache::kind(msg))
+}
+
+/// Render a deleted message that is kept around, according to weecord.main.deleted_messages
+fn render_deleted(weechat: &Weechat, msg: &Message, opts: FormatOptions) -> String {
+    let strikethrough = crate::upgrade_plugin(weechat).config.deleted_messages()
+        == crate::config::DeletedMessages::Strikethrough;
+    let mut text = formatting::discord_to_plain(weechat, &msg.content, opts);
+    for attachment in &msg.attachments {
+        if !text.is_empty() {
+            text.push('\n');
         }
-        for field in &embed.fields {
-            msg_content.push_str(&field.name);
-            msg_content.push_str(&field.value);
-            msg_content.push('\n');
+        text.push_str(&attachment.filename);
+    }
+    if strikethrough {
+        // Combine a long stroke overlay with every character, as weechat can't strike text through
+        text = text
+            .chars()
+            .flat_map(|c| match c {
+                '\n' => vec![c],
+                c => vec![c, '\u{336}'],
+            })
+            .collect();
+    }
+
+    // Weechat doesn't carry colors over to the next line, so every line has to be colored
+    let (grey, reset) = (weechat.color("8"), weechat.color("reset"));
+    let mut content = text
+        .lines()
+        .map(|line| format!("{}{}{}", grey, line, reset))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if !content.is_empty() {
+        content.push(' ');
+    }
+    content.push_str(&format!(
+        "{}(deleted){}",
+        weechat.color("red"),
+        weechat.color("reset")
+    ));
+    content
+}
+
+/// Render an attachment as its filename and url, followed by whatever metadata is known
+fn render_attachment(weechat: &Weechat, attachment: &Attachment) -> String {
+    let mut details = vec![utils::human_size(attachment.size)];
+    if let Some(content_type) = content_type(&attachment.filename) {
+        details.push(content_type.to_owned());
+    }
+    if let (Some(width), Some(height)) = (attachment.width, attachment.height) {
+        details.push(format!("{}x{}", width, height));
+    }
+
+    format!(
+        "{}{}{} {}({}){} <{}>",
+        weechat.color("bold"),
+        attachment.filename,
+        weechat.color("-bold"),
+        weechat.color("8"),
+        details.join(", "),
+        weechat.color("reset"),
+        attachment.url
+    )
+}
+
+/// Discord doesn't tell us the content type of attachments, so guess it from the extension
+fn content_type(filename: &str) -> Option<&'static str> {
+    let extension = filename.rsplit('.').next()?.to_lowercase();
+    let content_type = match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "flac" => "audio/flac",
+        "txt" | "log" => "text/plain",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        _ => return None,
+    };
+    Some(content_type)
+}
+
+/// Render an embed as a block of lines with a bar in the embed's colour down the left side
+fn render_embed(weechat: &Weechat, embed: &Embed, opts: FormatOptions) -> String {
+    let mut lines = Vec::new();
+    let (bold, unbold) = (weechat.color("bold"), weechat.color("-bold"));
+
+    if let Some(ref author) = embed.author {
+        lines.push(format!("{}{}{}", bold, author.name, unbold));
+    }
+    match (&embed.title, &embed.url) {
+        (Some(title), Some(url)) => lines.push(format!("{}{}{} <{}>", bold, title, unbold, url)),
+        (Some(title), None) => lines.push(format!("{}{}{}", bold, title, unbold)),
+        (None, Some(url)) => lines.push(url.clone()),
+        (None, None) => {}
+    }
+    if let Some(ref description) = embed.description {
+        let description = formatting::discord_to_weechat(weechat, description, opts);
+        lines.extend(description.lines().map(str::to_owned));
+    }
+
+    let mut inline_row: Vec<&EmbedField> = Vec::new();
+    for field in &embed.fields {
+        if field.inline && inline_row.len() < MAX_INLINE_FIELDS {
+            inline_row.push(field);
+            continue;
         }
-        if let Some(ref footer) = embed.footer {
-            msg_content.push_str(&footer.text);
-            msg_content.push('\n');
+        lines.extend(render_embed_fields(weechat, opts, &inline_row));
+        inline_row.clear();
+        if field.inline {
+            inline_row.push(field);
+        } else {
+            lines.extend(render_embed_fields(weechat, opts, &[field]));
         }
     }
+    lines.extend(render_embed_fields(weechat, opts, &inline_row));
 
-    let author = author_display_name(cache, &msg, guild);
+    if let Some(ref image) = embed.image {
+        lines.push(format!("Image: {}", image.url));
+    }
+    if let Some(ref thumbnail) = embed.thumbnail {
+        lines.push(format!("Thumbnail: {}", thumbnail.url));
+    }
 
-    use MessageType::*;
-    match msg.kind {
-        Regular => {
-            return (
-                author,
-                formatting::discord_to_weechat(weechat, &msg_content),
+    let timestamp = embed
+        .timestamp
+        .as_ref()
+        .and_then(|ts| chrono::DateTime::parse_from_rfc3339(ts).ok())
+        .map(|ts| {
+            ts.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        });
+    let footer = embed.footer.as_ref().map(|footer| footer.text.clone());
+    match (footer, timestamp) {
+        (Some(footer), Some(timestamp)) => lines.push(format!("{} • {}", footer, timestamp)),
+        (Some(line), None) | (None, Some(line)) => lines.push(line),
+        (None, None) => {}
+    }
+
+    let bar_color = if embed.colour.0 == 0 {
+        "8".to_owned()
+    } else {
+        utils::color_name(weechat, embed.colour)
+    };
+    let bar = format!("{}▎{}", weechat.color(&bar_color), weechat.color("reset"));
+    lines
+        .iter()
+        .map(|line| format!("{}{}", bar, line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Render embed fields as `name: value`, side by side if they are short enough
+fn render_embed_fields(
+    weechat: &Weechat,
+    opts: FormatOptions,
+    fields: &[&EmbedField],
+) -> Vec<String> {
+    let rendered = fields
+        .iter()
+        .map(|field| {
+            format!(
+                "{}{}:{} {}",
+                weechat.color("bold"),
+                field.name,
+                weechat.color("-bold"),
+                formatting::discord_to_weechat(weechat, &field.value, opts)
             )
-        }
+        })
+        .collect::<Vec<_>>();
+
+    let fits_on_one_line = fields.len() > 1
+        && fields
+            .iter()
+            .map(|field| field.name.chars().count() + field.value.chars().count() + 5)
+            .sum::<usize>()
+            <= MAX_EMBED_WIDTH
+        && fields.iter().all(|field| !field.value.contains('\n'));
+
+    if fits_on_one_line {
+        vec![rendered.join("  ")]
+    } else {
+        rendered
+            .iter()
+            .flat_map(|field| field.lines().map(str::to_owned).collect::<Vec<_>>())
+            .collect()
+    }
+}
+
+/// Render a single dimmed line previewing the message `msg` is replying to
+fn render_reply_preview(
+    cache: &CacheRwLock,
+    weechat: &Weechat,
+    msg: &Message,
+    guild: Option<GuildId>,
+) -> Option<String> {
+    let reference = msg.message_reference.as_ref()?;
+    // Crossposts also carry a reference, but to a message in another channel
+    if reference.channel_id != msg.channel_id {
+        return None;
+    }
+    let referenced_id = reference.message_id?;
 
-        _ => {
-            let (prefix, body) = match msg.kind {
-                GroupRecipientAddition | MemberJoin => {
-                    ("join", format!("{} joined the group.", author))
-                }
-                GroupRecipientRemoval => ("quit", format!("{} left the group.", author)),
-                GroupNameUpdate => (
-                    "network",
-                    format!("{} changed the channel name: {}.", author, msg.content),
-                ),
-                GroupCallCreation => ("network", format!("{} started a call.", author)),
-                GroupIconUpdate => ("network", format!("{} changed the channel icon.", author)),
-                PinsAdd => (
-                    "network",
-                    format!("{} pinned a message to this channel", author),
-                ),
-                NitroBoost => (
-                    "network",
-                    format!("{} boosted this channel with nitro", author),
-                ),
-                NitroTier1 => (
-                    "network",
-                    format!("This channel has achieved nitro level 1"),
-                ),
-                NitroTier2 => (
-                    "network",
-                    format!("This channel has achieved nitro level 2"),
-                ),
-                NitroTier3 => (
-                    "network",
-                    format!("This channel has achieved nitro level 3"),
-                ),
-                Regular | __Nonexhaustive => unreachable!(),
+    // Fetching can take a while, so the reply is rendered again once it is done
+    let (channel_id, reply_id) = (msg.channel_id, msg.id);
+    let referenced =
+        message_cache::get_or_fetch_later(reference.channel_id, referenced_id, move || {
+            if let (Some(ctx), Some(reply)) = (
+                crate::discord::get_ctx(),
+                message_cache::get(channel_id, reply_id),
+            ) {
+                crate::discord::event_handler::rerender_message(ctx, reply);
+            }
+        });
+
+    let (fmt, reset) = (weechat.color("8"), weechat.color("reset"));
+    let preview = match referenced {
+        message_cache::Lookup::Found(referenced) => {
+            let author = author_display_name(cache, &referenced, guild);
+            let content = formatting::discord_to_plain(
+                weechat,
+                &referenced.content,
+                format_options(&referenced, guild),
+            );
+            let content = content.lines().collect::<Vec<_>>().join(" ");
+            let content = if content.chars().count() > REPLY_PREVIEW_LENGTH {
+                content
+                    .chars()
+                    .take(REPLY_PREVIEW_LENGTH)
+                    .chain(std::iter::once('…'))
+                    .collect()
+            } else {
+                content
             };
-            return (weechat.get_prefix(prefix).into_owned(), body);
+            format!("{}┌ {}: {}{}", fmt, author, content, reset)
+        }
+        message_cache::Lookup::Pending => format!("{}┌ …{}", fmt, reset),
+        message_cache::Lookup::Unavailable(message_cache::Unavailable::Deleted) => {
+            format!("{}┌ (deleted message){}", fmt, reset)
+        }
+        message_cache::Lookup::Unavailable(message_cache::Unavailable::Failed) => {
+            format!("{}┌ (unable to load message){}", fmt, reset)
         }
     };
+    Some(preview)
+}
+
+/// Render reaction counts like `[👍 3] [:custom: 1]`, highlighting our own reactions
+fn render_reactions(weechat: &Weechat, reactions: &[MessageReaction]) -> Option<String> {
+    if reactions.is_empty() {
+        return None;
+    }
+    let emoji_as_shortcodes = crate::upgrade_plugin(weechat)
+        .config
+        .emoji_as_shortcodes
+        .value();
+
+    Some(
+        reactions
+            .iter()
+            .map(|reaction| {
+                let emoji = match &reaction.reaction_type {
+                    ReactionType::Unicode(emoji) if emoji_as_shortcodes => {
+                        parsing::emoji::replace_emoji_with_shortcodes(emoji)
+                    }
+                    ReactionType::Unicode(emoji) => emoji.clone(),
+                    ReactionType::Custom { name, .. } => {
+                        format!(":{}:", name.as_ref().map(String::as_str).unwrap_or("?"))
+                    }
+                    _ => "?".to_owned(),
+                };
+                let color = if reaction.me { "bold" } else { "8" };
+                format!(
+                    "{}[{} {}]{}",
+                    weechat.color(color),
+                    emoji,
+                    reaction.count,
+                    weechat.color("reset")
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+    )
+}
+
+/// The author's name colored for use as a message prefix
+fn author_prefix(
+    weechat: &Weechat,
+    cache: &CacheRwLock,
+    msg: &Message,
+    guild: Option<GuildId>,
+) -> String {
+    let author = author_display_name(cache, msg, guild);
+    match author_color(weechat, cache, msg, guild) {
+        Some(color) => format!(
+            "{}{}{}",
+            weechat.color(&color),
+            author,
+            weechat.color("reset")
+        ),
+        None => author,
+    }
 }
 
 pub fn author_display_name(cache: &CacheRwLock, msg: &Message, guild: Option<GuildId>) -> String {
@@ -118,7 +539,16 @@ pub fn author_display_name(cache: &CacheRwLock, msg: &Message, guild: Option<Gui
     author
 }
 
-pub fn msg_tags(cache: &CacheRwLock, msg: &Message, notify: bool) -> Vec<String> {
+/// How a message from someone else notifies: as a highlight, a private message or a message
+fn notify_priority(
+    weechat: &Weechat,
+    cache: &CacheRwLock,
+    msg: &Message,
+    guild: Option<GuildId>,
+) -> Option<HotlistPriority> {
+    if msg.author.id == cache.read().user.id {
+        return None;
+    }
     let is_private = if let Some(channel) = msg.channel(cache) {
         if let Channel::Private(_) = channel {
             true
@@ -129,22 +559,132 @@ pub fn msg_tags(cache: &CacheRwLock, msg: &Message, notify: bool) -> Vec<String>
         false
     };
 
-    let self_mentioned = msg.mentions_user_id(cache.read().user.id);
+    Some(if is_highlighted(weechat, cache, msg, guild) {
+        HotlistPriority::Highlight
+    } else if is_private {
+        HotlistPriority::Private
+    } else {
+        HotlistPriority::Message
+    })
+}
+
+pub fn msg_tags(
+    weechat: &Weechat,
+    cache: &CacheRwLock,
+    msg: &Message,
+    guild: Option<GuildId>,
+    notify: bool,
+) -> Vec<String> {
+    let is_self = msg.author.id == cache.read().user.id;
 
     let mut tags = Vec::new();
-    if notify {
-        if self_mentioned {
-            tags.push("notify_highlight");
-        } else if is_private {
-            tags.push("notify_private");
-        } else {
-            tags.push("notify_message");
-        };
+    let priority = if notify {
+        notify_priority(weechat, cache, msg, guild)
     } else {
-        tags.push("notify_none");
+        None
+    };
+    tags.push(match priority {
+        Some(HotlistPriority::Highlight) => "notify_highlight",
+        Some(HotlistPriority::Private) => "notify_private",
+        Some(_) => "notify_message",
+        None => "notify_none",
+    });
+    if is_self {
+        tags.push("self_msg");
+    }
+
+    let mut tags: Vec<_> = tags.into_iter().map(|t| t.to_string()).collect();
+    // Weechat separates tags with commas, and filters match nicks without spaces
+    let nick = author_display_name(cache, msg, guild)
+        .replace(|c: char| c == ',' || c.is_whitespace(), "_");
+    let color = author_color(weechat, cache, msg, guild).unwrap_or_else(|| "default".into());
+    tags.push(format!("prefix_nick_{}", color));
+    tags.push(format!("nick_{}", nick));
+    tags.push(format!("host_{}", msg.author.id.0));
+    tags.push(format!("discord_msgid_{}", msg.id.0));
+    tags.push(format!("discord_author_{}", msg.author.id.0));
+    tags.push(format!("discord_channel_{}", msg.channel_id.0));
+    if !is_regular(msg) {
+        tags.push(SYSTEM_TAG.to_owned());
+    }
+    tags
+}
+
+/// Whether a message mentions the current user, a role they hold, @everyone or @here (if enabled
+/// for the guild), or contains one of weecord.main.highlight_words
+fn is_highlighted(
+    weechat: &Weechat,
+    cache: &CacheRwLock,
+    msg: &Message,
+    guild: Option<GuildId>,
+) -> bool {
+    let config = &crate::upgrade_plugin(weechat).config;
+    let current_user = cache.read().user.id;
+
+    if msg.mentions_user_id(current_user) {
+        return true;
+    }
+
+    if let Some(guild) = guild {
+        if msg.mention_everyone && config.everyone_highlights(guild) {
+            return true;
+        }
+        if let Some(member) = cache.read().member(guild, current_user) {
+            if msg
+                .mention_roles
+                .iter()
+                .any(|role| member.roles.contains(role))
+            {
+                return true;
+            }
+        }
     }
 
-    tags.into_iter().map(|t| t.to_string()).collect()
+    let content = msg.content.to_lowercase();
+    config
+        .highlight_words()
+        .iter()
+        .any(|word| contains_word(&content, word))
+}
+
+/// Whether `word` appears in `text` without being part of a longer word
+fn contains_word(text: &str, word: &str) -> bool {
+    let is_word_char = |c: Option<char>| c.map_or(false, |c| c.is_alphanumeric() || c == '_');
+    text.match_indices(word).any(|(i, _)| {
+        !is_word_char(text[..i].chars().next_back())
+            && !is_word_char(text[i + word.len()..].chars().next())
+    })
+}
+
+/// The color of an author's nick, according to weecord.main.nick_colors
+fn author_color(
+    weechat: &Weechat,
+    cache: &CacheRwLock,
+    msg: &Message,
+    guild: Option<GuildId>,
+) -> Option<String> {
+    let role_color = || {
+        let member = cache.read().member(guild?, msg.author.id)?;
+        let role = utils::find_highest_colored_role(cache, &member)?;
+        Some(utils::color_name(weechat, role.colour))
+    };
+    let weechat_color = || {
+        weechat
+            .info_get("nick_color_name", &author_display_name(cache, msg, guild))
+            .map(|color| color.into_owned())
+    };
+
+    match crate::upgrade_plugin(weechat)
+        .config
+        .nick_colors
+        .value()
+        .as_ref()
+    {
+        "none" => None,
+        "role" => role_color(),
+        "weechat" => weechat_color(),
+        _ => role_color().or_else(weechat_color),
+    }
 }
 
 // TODO: Color things
@@ -157,32 +697,485 @@ pub fn print_msg(weechat: &Weechat, buffer: &Buffer, msg: &Message, notify: bool
         .get_localvar("guildid")
         .and_then(|id| id.parse::<u64>().ok().map(GuildId));
 
-    let (prefix, content) = render_msg(&ctx.cache, weechat, msg, maybe_guild);
+    message_cache::insert(msg);
+    let (mut prefix, content) = render_msg(&ctx.cache, weechat, msg, maybe_guild);
+    let previous_tags = last_line(buffer)
+        .map(|line| line_tags(&line))
+        .unwrap_or_default();
+    if continues_group(weechat, &previous_tags, msg) {
+        prefix = group_prefix(weechat);
+    }
     let timestamp = msg.timestamp.timestamp();
-    let tags = msg_tags(&ctx.cache, msg, notify).join(",");
+    let tags = msg_tags(weechat, &ctx.cache, msg, maybe_guild, notify).join(",");
     buffer.print_tags_dated(timestamp, &tags, &format!("{}\t{}", prefix, content));
+    if let Some(reactions) = reactions_line(weechat, &ctx.cache, msg, maybe_guild) {
+        buffer.print_tags_dated(
+            timestamp,
+            &reactions.tags,
+            &format!("\t{}", reactions.message),
+        );
+    }
+
+    track_newest_message(buffer, msg.id);
+}
+
+/// Whether a message should be grouped with the message printed before it, which is the case when
+/// neither is a system message and both are from the same author, sent within
+/// weecord.main.group_messages seconds of each other
+///
+/// The previous message is read from the tags of the line before the message rather than the
+/// message cache, so that a message that was deleted (and forgotten) but whose lines are still
+/// shown keeps its group. Grouping is decided when a message is printed or inserted, and for the
+/// message after inserted ones; as edits and deletes leave a message's lines and author in place,
+/// the prefixes chosen then stay correct
+fn continues_group(weechat: &Weechat, previous_tags: &[String], msg: &Message) -> bool {
+    let window = crate::upgrade_plugin(weechat).config.group_messages.value();
+    if window <= 0 || !is_regular(msg) {
+        return false;
+    }
+
+    if previous_tags.iter().any(|tag| tag == SYSTEM_TAG) {
+        return false;
+    }
+    let (previous_id, previous_author) = match (
+        tag_id(previous_tags, "discord_msgid_").map(MessageId),
+        tag_id(previous_tags, "discord_author_").map(UserId),
+    ) {
+        (Some(id), Some(author)) => (id, author),
+        _ => return false,
+    };
+
+    // Message ids contain the time they were sent
+    let elapsed = msg.timestamp.timestamp() - previous_id.created_at().timestamp();
+    previous_author == msg.author.id && elapsed >= 0 && elapsed <= i64::from(window)
 }
 
-// Use the `date_printed` hdata field to store the message id in the last message
-pub fn inject_msg_id(msg_id: MessageId, buffer: &Buffer) {
+/// The prefix of a message that continues the group of the one before it
+fn group_prefix(weechat: &Weechat) -> String {
+    crate::upgrade_plugin(weechat)
+        .config
+        .group_prefix
+        .value()
+        .into_owned()
+}
+
+/// Track the newest message so anything missed while disconnected can be fetched later
+pub fn track_newest_message(buffer: &Buffer, msg_id: MessageId) {
+    let newest = buffer
+        .get_localvar("newest_msgid")
+        .and_then(|id| id.parse::<u64>().ok())
+        .unwrap_or(0);
+    if msg_id.0 > newest {
+        buffer.set_localvar("newest_msgid", &msg_id.0.to_string());
+    }
+}
+
+/// Find the `line_data` of every line of a message, in the order they are displayed, not
+/// including its reactions
+pub fn find_message_lines(buffer: &Buffer, message_id: MessageId) -> Vec<HData> {
+    let mut lines = find_tagged_lines(buffer, &format!("discord_msgid_{}", message_id.0));
+    lines.retain(|line| !is_reactions_line(line));
+    lines
+}
+
+fn is_reactions_line(line_data: &HData) -> bool {
+    line_tags(line_data).iter().any(|tag| tag == REACTIONS_TAG)
+}
+
+/// The line shown below a message with its reactions, if it has any
+fn reactions_line(
+    weechat: &Weechat,
+    cache: &CacheRwLock,
+    msg: &Message,
+    guild: Option<GuildId>,
+) -> Option<LineContent> {
+    let reactions = render_reactions(weechat, &msg.reactions)?;
+    let mut tags = msg_tags(weechat, cache, msg, guild, false);
+    // Reactions change, so logging them as they were first printed would be misleading
+    tags.push("no_log".to_owned());
+    tags.push(REACTIONS_TAG.to_owned());
+    Some(LineContent {
+        date: msg.timestamp.timestamp(),
+        date_printed: chrono::Utc::now().timestamp(),
+        tags: tags.join(","),
+        prefix: String::new(),
+        message: reactions,
+    })
+}
+
+/// Show the current reactions of a printed message on the line below it, adding that line if the
+/// message had none before
+///
+/// Lines can't be removed, so a message that loses all of its reactions is left with a blank line
+pub fn update_reactions(weechat: &Weechat, buffer: &Buffer, msg: &Message) {
+    let ctx = match crate::discord::get_ctx() {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+    let guild = buffer
+        .get_localvar("guildid")
+        .and_then(|id| id.parse::<u64>().ok().map(GuildId));
+
+    let lines = find_tagged_lines(buffer, &format!("discord_msgid_{}", msg.id.0));
+    if let Some(line) = lines.iter().find(|line| is_reactions_line(line)) {
+        let reactions = render_reactions(weechat, &msg.reactions).unwrap_or_default();
+        line.update_var("message", reactions);
+        return;
+    }
+
+    let reactions = match reactions_line(weechat, &ctx.cache, msg, guild) {
+        Some(reactions) => reactions,
+        None => return,
+    };
+    let index = all_lines(buffer)
+        .iter()
+        .rposition(|line| line_message_id(line) == Some(msg.id));
+    if let Some(index) = index {
+        insert_lines(buffer, index + 1, vec![reactions]);
+    }
+}
+
+/// Find the `line_data` of the last line in a buffer
+fn last_line(buffer: &Buffer) -> Option<HData> {
+    let buffer_hdata = buffer.get_hdata("buffer")?;
+    let lines_ptr: HDataPointer = buffer_hdata.get_var("own_lines")?;
+    let lines_hdata = lines_ptr.get_hdata("lines")?;
+    let last_line_ptr: HDataPointer = lines_hdata.get_var("last_line")?;
+    let last_line_hdata = last_line_ptr.get_hdata("line")?;
+    let line_data_ptr: HDataPointer = last_line_hdata.get_var("data")?;
+    line_data_ptr.get_hdata("line_data")
+}
+
+/// Find the `line_data` of the last group of consecutive lines with a tag
+pub fn find_tagged_lines(buffer: &Buffer, tag: &str) -> Vec<HData> {
     let buffer_hdata = buffer.get_hdata("buffer").unwrap();
     let lines_ptr: HDataPointer = buffer_hdata.get_var("own_lines").unwrap();
     let lines_hdata = lines_ptr.get_hdata("lines").unwrap();
     let mut maybe_last_line_ptr = lines_hdata.get_var::<HDataPointer>("last_line");
 
+    let mut lines = Vec::new();
     while let Some(last_line_ptr) = maybe_last_line_ptr {
         let last_line_hdata = last_line_ptr.get_hdata("line").unwrap();
         let line_data_ptr: HDataPointer = last_line_hdata.get_var("data").unwrap();
-
         let line_data_hdata = line_data_ptr.get_hdata("line_data").unwrap();
-        line_data_hdata.update_var("date_printed", msg_id.0.to_string());
 
-        if let Some(prefix) = unsafe { line_data_hdata.get_string_unchecked("prefix") } {
-            if !prefix.is_empty() {
-                break;
-            }
+        if line_tags(&line_data_hdata).iter().any(|t| t == tag) {
+            lines.push(line_data_hdata);
+        } else if !lines.is_empty() {
+            // All lines of a message are printed together
+            break;
         }
 
         maybe_last_line_ptr = last_line_ptr.advance(&last_line_hdata, -1);
     }
+
+    lines.reverse();
+    lines
+}
+
+/// Replace the content of already printed lines
+///
+/// Lines cannot be added to a buffer after the fact, so any extra lines are joined onto the
+/// last one
+pub fn rewrite_lines(lines: &[HData], new_content: &str) {
+    let new_lines = new_content.splitn(lines.len(), "\n");
+    let new_lines = new_lines.map(|l| l.replace("\n", " | "));
+    let new_lines = new_lines.chain(std::iter::repeat("".to_owned()));
+
+    for (line_ptr, new_line) in lines.iter().zip(new_lines) {
+        line_ptr.update_var("message", new_line);
+    }
+}
+
+/// The parts of a printed line that can be changed afterwards
+pub struct LineContent {
+    pub date: i64,
+    pub date_printed: i64,
+    pub tags: String,
+    pub prefix: String,
+    pub message: String,
+}
+
+impl LineContent {
+    fn read(line_data: &HData) -> LineContent {
+        let string = |name| unsafe {
+            line_data
+                .get_string_unchecked(name)
+                .map(|value| value.to_string())
+                .unwrap_or_default()
+        };
+        LineContent {
+            date: unsafe { line_data.get_time_unchecked("date") },
+            date_printed: unsafe { line_data.get_time_unchecked("date_printed") },
+            tags: line_tags(line_data).join(","),
+            prefix: string("prefix"),
+            message: string("message"),
+        }
+    }
+
+    fn write(&self, line_data: &HData) {
+        // Whether a line highlights or notifies was decided for whatever it held before, so
+        // that is cleared rather than left on the wrong line
+        line_data.update_var("highlight", "0".to_owned());
+        line_data.update_var("notify_level", "0".to_owned());
+        line_data.update_var("date", self.date.to_string());
+        line_data.update_var("date_printed", self.date_printed.to_string());
+        line_data.update_var("tags_array", self.tags.clone());
+        line_data.update_var("prefix", self.prefix.clone());
+        line_data.update_var("message", self.message.clone());
+    }
+}
+
+/// The lines `print_msg` would print for a message, after a line with `previous_tags`
+fn msg_lines(
+    weechat: &Weechat,
+    buffer: &Buffer,
+    msg: &Message,
+    previous_tags: &[String],
+) -> Vec<LineContent> {
+    let ctx = match crate::discord::get_ctx() {
+        Some(ctx) => ctx,
+        _ => return Vec::new(),
+    };
+    let guild = buffer
+        .get_localvar("guildid")
+        .and_then(|id| id.parse::<u64>().ok().map(GuildId));
+
+    message_cache::insert(msg);
+    let (mut prefix, content) = render_msg(&ctx.cache, weechat, msg, guild);
+    if continues_group(weechat, previous_tags, msg) {
+        prefix = group_prefix(weechat);
+    }
+    let tags = msg_tags(weechat, &ctx.cache, msg, guild, false).join(",");
+    let now = chrono::Utc::now().timestamp();
+    let mut lines: Vec<_> = content
+        .split('\n')
+        .map(|line| LineContent {
+            date: msg.timestamp.timestamp(),
+            date_printed: now,
+            tags: tags.clone(),
+            prefix: prefix.clone(),
+            message: line.to_owned(),
+        })
+        .collect();
+    lines.extend(reactions_line(weechat, &ctx.cache, msg, guild));
+    lines
+}
+
+/// Whether any message has been printed to a buffer
+pub fn has_message_lines(buffer: &Buffer) -> bool {
+    all_lines(buffer)
+        .iter()
+        .any(|line| line_message_id(line).is_some())
+}
+
+/// Find the `line_data` of every line in a buffer, in the order they are displayed
+fn all_lines(buffer: &Buffer) -> Vec<HData> {
+    let buffer_hdata = buffer.get_hdata("buffer").unwrap();
+    let lines_ptr: HDataPointer = buffer_hdata.get_var("own_lines").unwrap();
+    let lines_hdata = lines_ptr.get_hdata("lines").unwrap();
+    let mut maybe_line_ptr = lines_hdata.get_var::<HDataPointer>("first_line");
+
+    let mut lines = Vec::new();
+    while let Some(line_ptr) = maybe_line_ptr {
+        let line_hdata = line_ptr.get_hdata("line").unwrap();
+        let line_data_ptr: HDataPointer = line_hdata.get_var("data").unwrap();
+        lines.push(line_data_ptr.get_hdata("line_data").unwrap());
+        maybe_line_ptr = line_ptr.advance(&line_hdata, 1);
+    }
+    lines
+}
+
+/// Whether the read marker of a buffer is after its last line
+fn all_lines_read(buffer: &Buffer) -> bool {
+    let buffer_hdata = buffer.get_hdata("buffer").unwrap();
+    let lines_ptr: HDataPointer = buffer_hdata.get_var("own_lines").unwrap();
+    let lines_hdata = lines_ptr.get_hdata("lines").unwrap();
+    match lines_hdata.get_var::<HDataPointer>("last_read_line") {
+        Some(read_ptr) => {
+            let read_hdata = read_ptr.get_hdata("line").unwrap();
+            read_ptr.advance(&read_hdata, 1).is_none()
+        }
+        None => false,
+    }
+}
+
+/// Insert lines into a buffer before the line at `index`
+///
+/// Weechat only adds lines at the end of a buffer, so this prints blank lines there and moves the
+/// lines from `index` onwards down to make room. Only what `LineContent` holds can be moved, so
+/// highlights and filters aren't reapplied to moved lines, and the read marker can't be moved
+/// with them: a buffer that was read up to its last line is marked read again, otherwise the
+/// marker ends up above where it was
+///
+/// Weechat drops the oldest lines of a buffer past weechat.history.max_buffer_lines_number, so
+/// no more new lines than that are inserted, keeping the newest
+pub fn insert_lines(buffer: &Buffer, index: usize, mut new_lines: Vec<LineContent>) {
+    let weechat = buffer.get_weechat();
+    let max_lines = utils::integer_option(&weechat, "weechat.history.max_buffer_lines_number")
+        .filter(|max| *max > 0)
+        .map_or(usize::MAX, |max| max as usize);
+    let excess = new_lines.len().saturating_sub(max_lines);
+    new_lines.drain(..excess);
+    if new_lines.is_empty() {
+        return;
+    }
+    let was_read = all_lines_read(buffer);
+    let old_count = all_lines(buffer).len();
+
+    for _ in 0..new_lines.len() {
+        buffer.print_tags_dated(0, "notify_none,no_log", "");
+    }
+    let lines = all_lines(buffer);
+    // Lines weechat dropped from the top move the insertion point up with them
+    let dropped = (old_count + new_lines.len()).saturating_sub(lines.len());
+    let last_index = match lines.len().checked_sub(new_lines.len()) {
+        Some(last_index) => last_index,
+        None => return,
+    };
+    let index = index.saturating_sub(dropped).min(last_index);
+
+    for i in (index + new_lines.len()..lines.len()).rev() {
+        LineContent::read(&lines[i - new_lines.len()]).write(&lines[i]);
+    }
+    for (line_data, content) in lines[index..].iter().zip(&new_lines) {
+        content.write(line_data);
+    }
+
+    if was_read {
+        buffer.mark_read();
+    }
+}
+
+/// Print messages that are older than some already printed, each before the first line of a newer
+/// message, skipping any that are already printed
+///
+/// Inserted lines can't notify like printed ones, so with `notify` the buffer is added to the
+/// hotlist for them instead
+pub fn insert_messages(weechat: &Weechat, buffer: &Buffer, msgs: &[Message], notify: bool) {
+    let lines = all_lines(buffer);
+    let line_ids: Vec<_> = lines.iter().map(line_message_id).collect();
+    let printed: HashSet<_> = line_ids.iter().flatten().collect();
+    let position = |id: MessageId| {
+        line_ids
+            .iter()
+            .position(|line_id| line_id.map(|line_id| line_id > id).unwrap_or(false))
+            .unwrap_or(line_ids.len())
+    };
+
+    let mut msgs: Vec<_> = msgs
+        .iter()
+        .filter(|msg| !printed.contains(&msg.id))
+        .collect();
+    msgs.sort_by_key(|msg| msg.id);
+
+    // Insert from the bottom up so that the positions of earlier groups stay the same
+    let mut groups: Vec<(usize, Vec<LineContent>)> = Vec::new();
+    for msg in &msgs {
+        let index = position(msg.id);
+        // Each message follows the one inserted before it, or the line it is inserted after
+        let previous_tags = match groups.last() {
+            Some((last_index, group)) if *last_index == index => group
+                .last()
+                .map(|line| line.tags.split(',').map(str::to_owned).collect())
+                .unwrap_or_default(),
+            _ => index
+                .checked_sub(1)
+                .map(|i| line_tags(&lines[i]))
+                .unwrap_or_default(),
+        };
+        let new_lines = msg_lines(weechat, buffer, msg, &previous_tags);
+        match groups.last_mut() {
+            Some((last_index, group)) if *last_index == index => group.extend(new_lines),
+            _ => groups.push((index, new_lines)),
+        }
+    }
+    // The message after each group now follows a different one
+    let following: Vec<_> = groups
+        .iter()
+        .filter_map(|(index, group)| {
+            let id = line_ids.get(*index).copied().flatten()?;
+            let tags = group.last()?.tags.split(',').map(str::to_owned).collect();
+            Some((id, tags))
+        })
+        .collect();
+    for (index, lines) in groups.into_iter().rev() {
+        insert_lines(buffer, index, lines);
+    }
+    if let Some(channel_id) = msgs.first().map(|msg| msg.channel_id) {
+        for (id, previous_tags) in following {
+            regroup_message(weechat, buffer, channel_id, id, &previous_tags);
+        }
+    }
+
+    for msg in &msgs {
+        track_newest_message(buffer, msg.id);
+    }
+    let ctx = match crate::discord::get_ctx() {
+        Some(ctx) if notify => ctx,
+        _ => return,
+    };
+    let guild = buffer
+        .get_localvar("guildid")
+        .and_then(|id| id.parse::<u64>().ok().map(GuildId));
+    for msg in &msgs {
+        if let Some(priority) = notify_priority(weechat, &ctx.cache, msg, guild) {
+            buffer.set_hotlist(priority);
+        }
+    }
+}
+
+/// Choose the prefix of a printed message again, after the line before it has changed
+fn regroup_message(
+    weechat: &Weechat,
+    buffer: &Buffer,
+    channel_id: ChannelId,
+    message_id: MessageId,
+    previous_tags: &[String],
+) {
+    let (ctx, msg) = match (
+        crate::discord::get_ctx(),
+        message_cache::get(channel_id, message_id),
+    ) {
+        (Some(ctx), Some(msg)) => (ctx, msg),
+        _ => return,
+    };
+    let prefix = if continues_group(weechat, previous_tags, &msg) {
+        group_prefix(weechat)
+    } else {
+        let guild = buffer
+            .get_localvar("guildid")
+            .and_then(|id| id.parse::<u64>().ok().map(GuildId));
+        render_msg(&ctx.cache, weechat, &msg, guild).0
+    };
+    for line in find_message_lines(buffer, message_id) {
+        line.update_var("prefix", prefix.clone());
+    }
+}
+
+/// Get the tags of a line from its `line_data`
+pub fn line_tags(line_data: &HData) -> Vec<String> {
+    let count = unsafe { line_data.get_i32_unchecked("tags_count") };
+    (0..count)
+        .filter_map(|i| unsafe { line_data.get_string_unchecked(&format!("{}|tags_array", i)) })
+        .map(|tag| tag.to_string())
+        .collect()
+}
+
+/// Get the id of the message a line belongs to from its `discord_msgid_<id>` tag
+pub fn line_message_id(line_data: &HData) -> Option<MessageId> {
+    line_tag_id(line_data, "discord_msgid_").map(MessageId)
+}
+
+/// Get the id from a line's tag like `discord_author_<id>`, given the part before the id
+fn line_tag_id(line_data: &HData, prefix: &str) -> Option<u64> {
+    tag_id(&line_tags(line_data), prefix)
+}
+
+/// Get the id from a tag like `discord_author_<id>`, given the part before the id
+fn tag_id(tags: &[String], prefix: &str) -> Option<u64> {
+    tags.iter()
+        .filter(|tag| tag.starts_with(prefix))
+        .filter_map(|tag| tag[prefix.len()..].parse().ok())
+        .next()
 }
@@ -7,6 +7,7 @@ use serenity::{
     model::{id::ChannelId, prelude::*},
     prelude::*,
 };
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use weechat::{Buffer, ConfigOption, Weechat};
 
@@ -34,11 +35,95 @@ impl PartialEq<ChannelId> for GuildOrChannel {
     }
 }
 
+/// Format a number of bytes using the largest fitting binary unit, e.g. `1.5 MiB`
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Expand a leading `~/` in a path to the user's home directory
+pub fn expand_home(path: &str) -> String {
+    // TODO: Find a better way to expand paths
+    if path.starts_with("~/") {
+        let dir = match dirs::home_dir() {
+            Some(dir) => dir.to_string_lossy().into_owned(),
+            None => ".".to_owned(),
+        };
+        format!("{}/{}", dir, &path[2..])
+    } else {
+        path.to_owned()
+    }
+}
+
+/// Where to save a downloaded file in `dir`, or `None` if the filename isn't usable
+///
+/// Filenames are chosen by whoever sent the file, so only the last component is used so that
+/// nothing can be written outside of `dir`, and a number is added instead of overwriting a file
+pub fn download_path(dir: &Path, filename: &str) -> Option<PathBuf> {
+    let filename = Path::new(filename).file_name()?;
+    let path = dir.join(filename);
+    if !path.exists() {
+        return Some(path);
+    }
+
+    let stem = path.file_stem()?.to_string_lossy().into_owned();
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|n| dir.join(format!("{}-{}{}", stem, n, extension)))
+        .find(|path| !path.exists())
+}
+
+/// Levels of each channel in the 6x6x6 color cube of 256 color terminals
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// The closest 256 color terminal color, from either the color cube or the grayscale ramp
 pub fn rgb_to_ansi(color: serenity::utils::Colour) -> u8 {
-    let r = (u16::from(color.r()) * 5 / 255) as u8;
-    let g = (u16::from(color.g()) * 5 / 255) as u8;
-    let b = (u16::from(color.b()) * 5 / 255) as u8;
-    16 + 36 * r + 6 * g + b
+    let (r, g, b) = (color.r(), color.g(), color.b());
+    let distance = |(r2, g2, b2): (u8, u8, u8)| {
+        let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2);
+        d(r, r2) + d(g, g2) + d(b, b2)
+    };
+
+    let nearest_level = |c: u8| {
+        (0..CUBE_LEVELS.len())
+            .min_by_key(|&i| (i32::from(CUBE_LEVELS[i]) - i32::from(c)).abs())
+            .unwrap_or(0)
+    };
+    let (ri, gi, bi) = (nearest_level(r), nearest_level(g), nearest_level(b));
+    let cube = (CUBE_LEVELS[ri], CUBE_LEVELS[gi], CUBE_LEVELS[bi]);
+
+    // The grayscale ramp goes from 8 to 238 in steps of 10
+    let average = (u16::from(r) + u16::from(g) + u16::from(b)) / 3;
+    let grey_index = (average.saturating_sub(3) / 10).min(23) as u8;
+    let grey = 8 + 10 * grey_index;
+
+    if distance((grey, grey, grey)) < distance(cube) {
+        232 + grey_index
+    } else {
+        (16 + 36 * ri + 6 * gi + bi) as u8
+    }
+}
+
+/// A weechat color name for an rgb color, using truecolor if weechat supports it
+pub fn color_name(weechat: &Weechat, color: serenity::utils::Colour) -> String {
+    let hex = format!("#{:02x}{:02x}{:02x}", color.r(), color.g(), color.b());
+    if weechat.color(&hex).is_empty() {
+        rgb_to_ansi(color).to_string()
+    } else {
+        hex
+    }
 }
 
 pub fn status_is_online(status: OnlineStatus) -> bool {
@@ -117,6 +202,14 @@ pub fn find_highest_roles(cache: &CacheRwLock, member: &Member) -> Option<(Role,
     Some((highest_hoisted?.clone(), highest?.clone()))
 }
 
+/// The highest role of a member that has a color
+pub fn find_highest_colored_role(cache: &CacheRwLock, member: &Member) -> Option<Role> {
+    let mut roles = member.roles(cache)?;
+    roles.sort();
+    // Roles without a colour have a colour of 0
+    roles.into_iter().filter(|role| role.colour.0 != 0).last()
+}
+
 pub fn unique_id(guild: Option<GuildId>, channel: ChannelId) -> String {
     if let Some(guild) = guild {
         format!("G{:?}C{}", guild.0, channel.0)
@@ -170,6 +263,132 @@ pub unsafe fn buffer_from_ptr(buffer_ptr: *mut std::ffi::c_void) -> Buffer {
     )
 }
 
+/// The command a key is bound to in a context like "default" or "cursor", if it is bound
+pub fn key_binding(weechat: &Weechat, context: &str, key: &str) -> Option<String> {
+    use std::ffi::{CStr, CString};
+
+    let plugin = weechat.as_ptr();
+    let name = CString::new("key").ok()?;
+    let arguments = CString::new(context).ok()?;
+    let key_var = CString::new("key").ok()?;
+    let command_var = CString::new("command").ok()?;
+
+    unsafe {
+        let infolist_get = (*plugin).infolist_get?;
+        let infolist_next = (*plugin).infolist_next?;
+        let infolist_string = (*plugin).infolist_string?;
+        let infolist_free = (*plugin).infolist_free?;
+
+        let infolist = infolist_get(
+            plugin,
+            name.as_ptr(),
+            std::ptr::null_mut(),
+            arguments.as_ptr(),
+        );
+        if infolist.is_null() {
+            return None;
+        }
+        let string = |var: &CString| {
+            let value = infolist_string(infolist, var.as_ptr());
+            if value.is_null() {
+                None
+            } else {
+                Some(CStr::from_ptr(value).to_string_lossy().into_owned())
+            }
+        };
+
+        let mut command = None;
+        while infolist_next(infolist) != 0 {
+            if string(&key_var).map_or(false, |bound| bound == key) {
+                command = string(&command_var);
+                break;
+            }
+        }
+        infolist_free(infolist);
+        command
+    }
+}
+
+/// The value of an integer option from weechat or another plugin, like
+/// "weechat.history.max_buffer_lines_number"
+pub fn integer_option(weechat: &Weechat, name: &str) -> Option<i32> {
+    let plugin = weechat.as_ptr();
+    let name = std::ffi::CString::new(name).ok()?;
+
+    unsafe {
+        let config_get = (*plugin).config_get?;
+        let config_integer = (*plugin).config_integer?;
+
+        let option = config_get(name.as_ptr());
+        if option.is_null() {
+            None
+        } else {
+            Some(config_integer(option))
+        }
+    }
+}
+
+/// Bind a key unless it is already bound to something else, returning whether it is now bound to
+/// `command`
+pub fn bind_key(weechat: &Weechat, context: &str, key: &str, command: &str) -> bool {
+    match key_binding(weechat, context, key) {
+        Some(bound) => bound == command,
+        None => {
+            run_core_command(
+                weechat,
+                &format!("/key bindctxt {} {} {}", context, key, command),
+            );
+            true
+        }
+    }
+}
+
+/// Unbind a key if it is still bound to `command`, leaving it alone if the user rebound it
+pub fn unbind_key(weechat: &Weechat, context: &str, key: &str, command: &str) {
+    if key_binding(weechat, context, key).map_or(false, |bound| bound == command) {
+        run_core_command(weechat, &format!("/key unbindctxt {} {}", context, key));
+    }
+}
+
+/// Run a command in the core buffer, for commands like `/key` that don't need a buffer
+pub fn run_core_command(weechat: &Weechat, command: &str) {
+    if let Some(core) = weechat.buffer_search("core", "weechat") {
+        core.run_command(command);
+    }
+}
+
+/// Copy text with the first clipboard tool that works, returning whether one did
+pub fn copy_to_clipboard(text: &str) -> bool {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    const TOOLS: &[(&str, &[&str])] = &[
+        ("wl-copy", &[]),
+        ("xclip", &["-selection", "clipboard"]),
+        ("xsel", &["--clipboard", "--input"]),
+        ("pbcopy", &[]),
+    ];
+    TOOLS.iter().any(|(tool, args)| {
+        let mut child = match Command::new(tool)
+            .args(*args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(_) => return false,
+        };
+        // Taking stdin closes it once written, which the tools wait for
+        let written = child
+            .stdin
+            .take()
+            .map_or(false, |mut stdin| stdin.write_all(text.as_bytes()).is_ok());
+        let succeeded = child.wait().map_or(false, |status| status.success());
+        written && succeeded
+    })
+}
+
 pub fn buffer_is_muted(buffer: &Buffer) -> bool {
     if let Some(muted) = buffer.get_localvar("muted") {
         muted == "1"
@@ -243,38 +462,260 @@ pub fn flatten_guilds(
     channels
 }
 
+/// Messages are fetched this many at a time when looking for the nth message
+const MESSAGE_PAGE_SIZE: u64 = 100;
+/// Stop looking for the nth message after this many messages
+const MAX_SEARCHED_MESSAGES: usize = 1000;
+
 pub fn get_users_nth_message(
     ctx: &Context,
     channel: ChannelId,
     n: usize,
 ) -> serenity::Result<Message> {
-    if n > 50 {
-        return Err(serenity::Error::ExceededLimit(
-            "Cannot fetch more than 50 items".into(),
-            n as u32,
-        ));
-    }
     let user = ctx.cache.read().user.id;
-    // TODO: Page if needed
-    channel
-        .messages(&ctx.http, |retriever| retriever.limit(50))
-        .and_then(|msgs| {
-            msgs.iter()
-                .filter(|msg| msg.author.id == user)
-                .nth(n - 1)
-                .cloned()
-                .ok_or(serenity::Error::Model(
-                    serenity::model::ModelError::ItemMissing,
+    nth_message_matching(ctx, channel, n, |msg| msg.author.id == user)
+}
+
+pub fn get_nth_message(ctx: &Context, channel: ChannelId, n: usize) -> serenity::Result<Message> {
+    nth_message_matching(ctx, channel, n, |_| true)
+}
+
+fn nth_message_matching(
+    ctx: &Context,
+    channel: ChannelId,
+    n: usize,
+    filter: impl Fn(&Message) -> bool,
+) -> serenity::Result<Message> {
+    let mut remaining = n.max(1);
+    let mut before = None;
+    let mut searched = 0;
+
+    while searched < MAX_SEARCHED_MESSAGES {
+        let query = match before {
+            Some(id) => format!("?limit={}&before={}", MESSAGE_PAGE_SIZE, id),
+            None => format!("?limit={}", MESSAGE_PAGE_SIZE),
+        };
+        let page = crate::message_cache::fetch_messages(&ctx.http, channel, &query)?;
+        // Messages are returned newest first
+        before = match page.last() {
+            Some(msg) => Some(msg.id),
+            None => break,
+        };
+        searched += page.len();
+
+        for msg in page {
+            if filter(&msg) {
+                remaining -= 1;
+                if remaining == 0 {
+                    return Ok(msg);
+                }
+            }
+        }
+    }
+
+    Err(serenity::Error::Model(
+        serenity::model::ModelError::ItemMissing,
+    ))
+}
+
+/// Get the message a command or line edit targets
+pub fn get_message(
+    ctx: &Context,
+    channel: ChannelId,
+    target: parsing::MessageTarget,
+) -> serenity::Result<Message> {
+    match target {
+        parsing::MessageTarget::Line(n) => get_nth_message(ctx, channel, n),
+        parsing::MessageTarget::Id(id) => {
+            crate::message_cache::get_or_fetch(ctx, channel, MessageId(id)).ok_or(
+                serenity::Error::Model(serenity::model::ModelError::ItemMissing),
+            )
+        }
+    }
+}
+
+/// Whether a request failed because what it asked for doesn't exist, or no longer exists
+pub fn is_not_found(error: &serenity::Error) -> bool {
+    match error {
+        serenity::Error::Http(error) => match &**error {
+            serenity::http::HttpError::UnsuccessfulRequest(response) => {
+                response.status_code.as_u16() == 404
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Get the message a line edit targets, which must have been sent by the current user
+pub fn get_users_message(
+    ctx: &Context,
+    channel: ChannelId,
+    target: parsing::MessageTarget,
+) -> serenity::Result<Message> {
+    match target {
+        parsing::MessageTarget::Line(n) => get_users_nth_message(ctx, channel, n),
+        parsing::MessageTarget::Id(_) => {
+            let msg = get_message(ctx, channel, target)?;
+            if msg.author.id == ctx.cache.read().user.id {
+                Ok(msg)
+            } else {
+                Err(serenity::Error::Model(
+                    serenity::model::ModelError::InvalidUser,
                 ))
-        })
+            }
+        }
+    }
+}
+
+/// Apply a sed style substitution, where `old` is a regex and `new` can refer to its groups as
+/// `\1` and to the whole match as `&`
+///
+/// Only the first match is replaced unless `options` contains `g`, and `i` makes the match case
+/// insensitive
+pub fn apply_substitution(
+    content: &str,
+    old: &str,
+    new: &str,
+    options: Option<&str>,
+) -> Result<String, regex::Error> {
+    let options = options.unwrap_or_default();
+    let regex = regex::RegexBuilder::new(old)
+        .case_insensitive(options.contains('i'))
+        .build()?;
+
+    // Translate sed's replacement syntax to the regex crate's
+    let mut replacement = String::new();
+    let mut chars = new.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '$' => replacement.push_str("$$"),
+            '&' => replacement.push_str("${0}"),
+            '\\' => match chars.next() {
+                Some(digit) if digit.is_ascii_digit() => {
+                    replacement.push_str(&format!("${{{}}}", digit))
+                }
+                Some('n') => replacement.push('\n'),
+                Some(other) => replacement.push(other),
+                None => replacement.push('\\'),
+            },
+            c => replacement.push(c),
+        }
+    }
+
+    Ok(if options.contains('g') {
+        regex
+            .replace_all(content, replacement.as_str())
+            .into_owned()
+    } else {
+        regex.replace(content, replacement.as_str()).into_owned()
+    })
+}
+
+pub fn react_to_message(
+    ctx: &Context,
+    channel: ChannelId,
+    guild_id: Option<GuildId>,
+    target: parsing::MessageTarget,
+    emoji: &str,
+    remove: bool,
+) -> serenity::Result<()> {
+    let reaction = parse_reaction(&ctx.cache, guild_id, emoji)
+        .ok_or(serenity::Error::Other("Unknown emoji"))?;
+    let message_id = match target {
+        parsing::MessageTarget::Line(n) => get_nth_message(ctx, channel, n)?.id,
+        // No need to fetch the message just for its id
+        parsing::MessageTarget::Id(id) => MessageId(id),
+    };
+
+    if remove {
+        channel.delete_reaction(&ctx.http, message_id, None, reaction)
+    } else {
+        channel.create_reaction(&ctx.http, message_id, reaction)
+    }
+}
+
+/// Resolve user input like `👍`, `:name:` or `name` to something that can be reacted with
+///
+/// Custom emoji are looked up by name in the given guild
+pub fn parse_reaction(
+    cache: &CacheRwLock,
+    guild_id: Option<GuildId>,
+    input: &str,
+) -> Option<ReactionType> {
+    let name = input.trim().trim_matches(':');
+    if name.is_empty() {
+        return None;
+    }
+
+    if let Some(guild) = guild_id.and_then(|g| g.to_guild_cached(cache)) {
+        for emoji in guild.read().emojis.values() {
+            if emoji.name == name {
+                return Some(ReactionType::Custom {
+                    animated: emoji.animated,
+                    id: emoji.id,
+                    name: Some(emoji.name.clone()),
+                });
+            }
+        }
+    }
+
+    if let Some(emoji) = parsing::emoji::emoji_for_shortcode(name) {
+        return Some(ReactionType::Unicode(emoji.to_owned()));
+    }
+
+    if name.is_ascii() {
+        None
+    } else {
+        Some(ReactionType::Unicode(name.to_owned()))
+    }
 }
 
 // TODO: Role mentions
-/// Parse user input and replace mentions with Discords internal representation
+/// Parse user input and replace mentions and emoji with Discords internal representation
 ///
 /// This is not in `parsing` because it depends on `serenity`
 pub fn create_mentions(cache: &CacheRwLock, guild_id: Option<GuildId>, input: &str) -> String {
-    let mut out = String::from(input);
+    lazy_static! {
+        static ref CODE_SPAN: Regex = Regex::new(r"(?s)```.*?```|`[^`]+`").unwrap();
+    }
+
+    // Mentions are left as they are inside of code
+    let mut out = String::new();
+    let mut last_end = 0;
+    for code in CODE_SPAN.find_iter(input) {
+        out.push_str(&create_mentions_in_text(
+            cache,
+            guild_id,
+            &input[last_end..code.start()],
+        ));
+        out.push_str(code.as_str());
+        last_end = code.end();
+    }
+    out.push_str(&create_mentions_in_text(
+        cache,
+        guild_id,
+        &input[last_end..],
+    ));
+    out
+}
+
+/// Replace `:shortcode:`s with unicode emoji, or the guild's custom emoji of that name
+fn create_emoji(cache: &CacheRwLock, guild_id: Option<GuildId>, input: &str) -> String {
+    let guild = guild_id.and_then(|g| g.to_guild_cached(cache));
+    parsing::emoji::replace_shortcodes(input, |name| {
+        if let Some(guild) = &guild {
+            if let Some(emoji) = guild.read().emojis.values().find(|e| e.name == name) {
+                let prefix = if emoji.animated { "a" } else { "" };
+                return Some(format!("<{}:{}:{}>", prefix, emoji.name, emoji.id));
+            }
+        }
+        parsing::emoji::emoji_for_shortcode(name).map(str::to_owned)
+    })
+}
+
+fn create_mentions_in_text(cache: &CacheRwLock, guild_id: Option<GuildId>, input: &str) -> String {
+    let mut out = create_emoji(cache, guild_id, input);
 
     lazy_static! {
         static ref CHANNEL_MENTION: Regex = Regex::new(r"#([a-z_-]+)").unwrap();